@@ -0,0 +1,250 @@
+//! `--early-start-bp`: load the matchlist in the background and start
+//! gather before it is done, overlapping file I/O with the rounds.
+//!
+//! The bound is a promise that no matchlist file still to be loaded
+//! overlaps the query by more than that. While loading is going on, a
+//! round only picks its best loaded match if it overlaps the remaining
+//! query by more than the bound, and otherwise waits for more candidates;
+//! newly loaded ones are rescored against the remaining query before they
+//! join the heap. With a bound that holds, the results are those of a run
+//! that loads everything first.
+
+use std::collections::{BinaryHeap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use log::{debug, info};
+use rayon::prelude::*;
+use sourmash::sketch::minhash::KmerMinHash;
+use sourmash::sketch::Sketch;
+
+use crate::index::{HeapIndex, Index};
+use crate::loader::{self, RetryPolicy};
+use crate::{load_candidate, screen, Candidate, Label, RankBy};
+
+/// Spaces file loads at least `interval` apart, across threads.
+struct Pacer {
+    interval: Duration,
+    next: Mutex<Instant>,
+}
+
+impl Pacer {
+    fn new(files_per_second: f64) -> Pacer {
+        Pacer {
+            interval: Duration::from_secs_f64(1.0 / files_per_second),
+            next: Mutex::new(Instant::now()),
+        }
+    }
+
+    fn wait(&self) {
+        let slot = {
+            let mut next = self.next.lock().unwrap();
+            let slot = (*next).max(Instant::now());
+            *next = slot + self.interval;
+            slot
+        };
+        std::thread::sleep(slot.saturating_duration_since(Instant::now()));
+    }
+}
+
+/// What the loading thread needs, owned so that it can outlive the caller.
+pub(crate) struct LoadJob {
+    pub(crate) paths: Vec<PathBuf>,
+    pub(crate) labels: HashMap<PathBuf, Label>,
+    pub(crate) template: Sketch,
+    pub(crate) query: KmerMinHash,
+    pub(crate) threshold_hashes: u64,
+    pub(crate) cache_dir: Option<PathBuf>,
+    pub(crate) retry: RetryPolicy,
+    /// files per second, if limited
+    pub(crate) load_rate: Option<f64>,
+    /// how many files to load at a time
+    pub(crate) threads: usize,
+}
+
+pub(crate) struct BackgroundIndex {
+    loaded: HeapIndex,
+    receiver: Receiver<Candidate>,
+    /// no more candidates will arrive
+    done: bool,
+    bound_hashes: u64,
+    cancel: Arc<AtomicBool>,
+    handle: Option<JoinHandle<Vec<PathBuf>>>,
+    /// rounds that had to wait for more candidates
+    n_waits: usize,
+}
+
+impl BackgroundIndex {
+    /// Start loading `job` in the background, on top of the already-loaded
+    /// `candidates`, and wait for a first candidate.
+    pub(crate) fn start(
+        job: LoadJob,
+        candidates: BinaryHeap<Candidate>,
+        bound_hashes: u64,
+        speculate: Option<usize>,
+    ) -> BackgroundIndex {
+        let (sender, receiver) = mpsc::channel();
+        let cancel = Arc::new(AtomicBool::new(false));
+        let stop = Arc::clone(&cancel);
+        let handle = std::thread::spawn(move || {
+            let failed = Mutex::new(vec![]);
+            let loader = loader::Loader::new(job.cache_dir.as_deref(), job.retry, &failed);
+            let screen = screen::QueryScreen::new(&job.query);
+            let pacer = job.load_rate.map(Pacer::new);
+            // a pool of its own: gather may block rayon workers waiting on
+            // candidates, e.g. for every query of a --query-list.
+            let pool = match rayon::ThreadPoolBuilder::new()
+                .num_threads(job.threads)
+                .build()
+            {
+                Ok(pool) => pool,
+                Err(e) => {
+                    log::error!("could not start background loading: {}", e);
+                    return vec![];
+                }
+            };
+            pool.install(|| {
+                job.paths.par_iter().for_each(|path: &PathBuf| {
+                    if stop.load(Ordering::Relaxed) {
+                        return;
+                    }
+                    if let Some(pacer) = &pacer {
+                        pacer.wait();
+                    }
+                    let result = load_candidate(
+                        path,
+                        &job.template,
+                        &screen,
+                        job.threshold_hashes,
+                        false,
+                        &loader,
+                    );
+                    if let Some(result) = result {
+                        // a closed channel means gather is over.
+                        let _ = sender.send(result.labelled(job.labels.get(path as &Path)));
+                    }
+                })
+            });
+            failed.into_inner().unwrap()
+        });
+
+        let mut index = BackgroundIndex {
+            loaded: HeapIndex::new(candidates, speculate),
+            receiver,
+            done: false,
+            bound_hashes,
+            cancel,
+            handle: Some(handle),
+            n_waits: 0,
+        };
+        if index.loaded.is_empty() {
+            index.wait_for_more(None, 0);
+        }
+        index
+    }
+
+    /// Block until a candidate arrives or loading is done, then take every
+    /// candidate that arrived, rescored against `query` if given.
+    fn wait_for_more(&mut self, query: Option<&KmerMinHash>, threshold_hashes: u64) {
+        match self.receiver.recv() {
+            Ok(candidate) => self.merge(vec![candidate], query, threshold_hashes),
+            Err(_) => self.done = true,
+        }
+        self.take_arrived(query, threshold_hashes);
+    }
+
+    /// Take every candidate that arrived without waiting.
+    fn take_arrived(&mut self, query: Option<&KmerMinHash>, threshold_hashes: u64) {
+        let mut arrived = vec![];
+        loop {
+            match self.receiver.try_recv() {
+                Ok(candidate) => arrived.push(candidate),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    self.done = true;
+                    break;
+                }
+            }
+        }
+        self.merge(arrived, query, threshold_hashes);
+    }
+
+    fn merge(
+        &mut self,
+        candidates: Vec<Candidate>,
+        query: Option<&KmerMinHash>,
+        threshold_hashes: u64,
+    ) {
+        match query {
+            Some(query) => self.loaded.extend(candidates, query, threshold_hashes),
+            // scored against the original query as loaded.
+            None => candidates.into_iter().for_each(|c| self.loaded.push(c)),
+        }
+    }
+}
+
+impl Index for BackgroundIndex {
+    /// The candidates loaded so far.
+    fn len(&self) -> usize {
+        self.loaded.len()
+    }
+
+    fn prefetch(&self) -> Vec<Candidate> {
+        self.loaded.prefetch()
+    }
+
+    /// Only `RankBy::Overlap` is supported.
+    fn best_match(
+        &mut self,
+        query: &KmerMinHash,
+        threshold_hashes: u64,
+        rank_by: RankBy,
+    ) -> Result<Option<Candidate>, Box<dyn std::error::Error>> {
+        loop {
+            self.take_arrived(Some(query), threshold_hashes);
+            if self.done {
+                return self.loaded.best_match(query, threshold_hashes, rank_by);
+            }
+            if let Some(best) = self.loaded.best_match(query, threshold_hashes, rank_by)? {
+                if best.containment > self.bound_hashes {
+                    return Ok(Some(best));
+                }
+                self.loaded.push(best);
+            }
+            self.n_waits += 1;
+            self.wait_for_more(Some(query), threshold_hashes);
+        }
+    }
+
+    fn remove_hashes(&mut self, query: &KmerMinHash, threshold_hashes: u64) {
+        self.loaded.remove_hashes(query, threshold_hashes);
+    }
+
+    fn stop_loading(&mut self) -> Vec<PathBuf> {
+        self.cancel.store(true, Ordering::Relaxed);
+        let failed = match self.handle.take() {
+            Some(handle) => handle.join().unwrap_or_default(),
+            None => vec![],
+        };
+        if !self.done {
+            info!("Gather finished before the matchlist was loaded; stopped loading");
+        }
+        failed
+    }
+
+    fn finish(&self) {
+        debug!("waited for background loading in {} rounds", self.n_waits);
+        self.loaded.finish();
+    }
+}
+
+impl Drop for BackgroundIndex {
+    /// Gather may stop early, e.g. on an error; don't keep loading.
+    fn drop(&mut self) {
+        self.cancel.store(true, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,143 @@
+//! Synthetic benchmarks of the gather engines.
+
+use std::time::Instant;
+
+use clap::Args;
+use log::info;
+
+use sourmash::encodings::HashFunctions;
+use sourmash::signature::SigsTrait;
+use sourmash::sketch::minhash::{max_hash_for_scaled, KmerMinHash};
+
+use crate::{compute, engine, Candidate, GatherResult};
+
+#[derive(Args, Debug)]
+pub struct BenchArgs {
+    /// number of synthetic matches
+    #[clap(long = "n-matches", default_value = "1000")]
+    n_matches: usize,
+
+    /// number of hashes in each match
+    #[clap(long = "match-size", default_value = "5000")]
+    match_size: usize,
+
+    /// fraction of each match's hashes shared with the previous match
+    #[clap(long = "overlap", default_value = "0.5")]
+    overlap: f64,
+
+    /// fraction of the matches included in the query
+    #[clap(long = "query-fraction", default_value = "0.1")]
+    query_fraction: f64,
+
+    /// extra query hashes not present in any match, as a fraction of the query
+    #[clap(long = "noise", default_value = "0.1")]
+    noise: f64,
+
+    /// scaled value of the synthetic sketches
+    #[clap(short = 's', long = "scaled", default_value = "1000")]
+    scaled: u64,
+
+    /// random seed for generating the sketches
+    #[clap(long = "random-seed", default_value = "1")]
+    random_seed: u64,
+
+    /// engine(s) to run
+    #[clap(
+        long = "engine",
+        default_value = "all",
+        possible_values = &["heap", "counter", "all"]
+    )]
+    engine: String,
+}
+
+/// splitmix64; good enough for synthetic hashes and no extra dependency.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    fn hash_below(&mut self, max_hash: u64) -> u64 {
+        self.next() % max_hash
+    }
+}
+
+/// Build a chain of matches where each shares `overlap` of its hashes with
+/// the previous one, and a query made from the first matches plus noise.
+fn synthetic_data(args: &BenchArgs) -> (KmerMinHash, Vec<Candidate>) {
+    let mut rng = Rng(args.random_seed);
+    let max_hash = max_hash_for_scaled(args.scaled);
+    let empty = compute::empty_minhash(31, args.scaled, HashFunctions::murmur64_DNA, 42, false);
+
+    let n_shared = (args.match_size as f64 * args.overlap.clamp(0.0, 1.0)) as usize;
+    let mut db = Vec::with_capacity(args.n_matches);
+    let mut previous: Vec<u64> = vec![];
+    for i in 0..args.n_matches {
+        let mut hashes: Vec<u64> = previous.iter().take(n_shared).cloned().collect();
+        while hashes.len() < args.match_size {
+            hashes.push(rng.hash_below(max_hash));
+        }
+        // shuffle so the next match shares a random subset.
+        for j in (1..hashes.len()).rev() {
+            hashes.swap(j, (rng.next() % (j as u64 + 1)) as usize);
+        }
+
+        let mut minhash = empty.clone();
+        minhash.add_many(&hashes).unwrap();
+        db.push(Candidate::new(format!("synthetic_{}", i), minhash, 0));
+        previous = hashes;
+    }
+
+    let n_query = (args.n_matches as f64 * args.query_fraction).round() as usize;
+    let mut query = empty;
+    for entry in db.iter().take(n_query) {
+        query.add_from(&entry.minhash).unwrap();
+    }
+    let n_noise = (query.size() as f64 * args.noise) as usize;
+    for _ in 0..n_noise {
+        query.add_hash(rng.hash_below(max_hash));
+    }
+
+    (query, db)
+}
+
+fn report(engine: &str, seconds: f64, query: &KmerMinHash, results: &[GatherResult]) {
+    let assigned: u64 = results.iter().map(|r| r.unique_intersect_hashes).sum();
+    println!(
+        "{}\t{:.3}\t{}\t{}\t{:.1}\t{:.0}",
+        engine,
+        seconds,
+        results.len(),
+        assigned,
+        results.len() as f64 / seconds,
+        query.size() as f64 / seconds
+    );
+}
+
+pub fn bench(args: &BenchArgs) -> Result<(), Box<dyn std::error::Error>> {
+    info!(
+        "Generating {} matches of {} hashes (overlap {})",
+        args.n_matches, args.match_size, args.overlap
+    );
+    let (query, db) = synthetic_data(args);
+    info!("Query has {} hashes", query.size());
+
+    println!("engine\tseconds\trounds\tassigned\trounds_per_s\tquery_hashes_per_s");
+    if args.engine == "heap" || args.engine == "all" {
+        let start = Instant::now();
+        let results = engine::heap_gather(query.clone(), &db, 0, |_| {})?;
+        report("heap", start.elapsed().as_secs_f64(), &query, &results);
+    }
+    if args.engine == "counter" || args.engine == "all" {
+        let start = Instant::now();
+        let results = engine::counter_gather(&query, &db, 0, |_| {})?;
+        report("counter", start.elapsed().as_secs_f64(), &query, &results);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,300 @@
+//! A binary cache of parsed signature files, so repeated runs against the
+//! same matchlist skip JSON parsing.
+//!
+//! Each cached file records the source path, size and modification time,
+//! followed by its sketches with the hashes (and abundances) stored as
+//! little-endian `u64` arrays. A cache file that doesn't match its source
+//! any more is ignored and rewritten.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::UNIX_EPOCH;
+
+use log::{debug, warn};
+
+use sourmash::encodings::HashFunctions;
+use sourmash::signature::{Signature, SigsTrait};
+use sourmash::sketch::minhash::{scaled_for_max_hash, KmerMinHash};
+use sourmash::sketch::Sketch;
+
+const MAGIC: &[u8; 8] = b"CGSKC001";
+
+/// Used to give concurrent writers distinct temporary files.
+static TMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// What a cache file must agree with to be used for a source file.
+#[derive(PartialEq)]
+struct SourceKey {
+    path: String,
+    size: u64,
+    mtime_secs: u64,
+    mtime_nanos: u32,
+}
+
+impl SourceKey {
+    fn for_path(path: &Path) -> io::Result<SourceKey> {
+        let path = path.canonicalize()?;
+        let meta = std::fs::metadata(&path)?;
+        let mtime = meta
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        Ok(SourceKey {
+            path: path.to_string_lossy().into_owned(),
+            size: meta.len(),
+            mtime_secs: mtime.as_secs(),
+            mtime_nanos: mtime.subsec_nanos(),
+        })
+    }
+
+    /// Named after the md5 of the source path, which unlike the std
+    /// hashers stays the same across Rust releases.
+    fn cache_file(&self, cache_dir: &Path) -> PathBuf {
+        let digest = md5::compute(self.path.as_bytes());
+        cache_dir.join(format!("{:x}.sketches", digest))
+    }
+}
+
+/// Load the signatures in `path`, going through the cache in `cache_dir`
+/// if there is one.
+pub(crate) fn load_signatures(
+    path: &Path,
+    cache_dir: Option<&Path>,
+) -> Result<Vec<Signature>, sourmash::Error> {
+    let cache_dir = match cache_dir {
+        Some(dir) => dir,
+        None => return Signature::from_path(path),
+    };
+    let key = match SourceKey::for_path(path) {
+        Ok(key) => key,
+        Err(_) => return Signature::from_path(path),
+    };
+    let cache_file = key.cache_file(cache_dir);
+
+    match read_cache(&cache_file, &key) {
+        Ok(Some(sigs)) => return Ok(sigs),
+        Ok(None) => {}
+        Err(e) => debug!("ignoring cache {}: {}", cache_file.display(), e),
+    }
+
+    let sigs = Signature::from_path(path)?;
+    if let Err(e) = write_cache(&cache_file, &key, &sigs) {
+        warn!("could not write cache {}: {}", cache_file.display(), e);
+    }
+    Ok(sigs)
+}
+
+fn hash_function_code(hash_function: HashFunctions) -> u8 {
+    match hash_function {
+        HashFunctions::murmur64_DNA => 0,
+        HashFunctions::murmur64_protein => 1,
+        HashFunctions::murmur64_dayhoff => 2,
+        HashFunctions::murmur64_hp => 3,
+    }
+}
+
+fn hash_function_from_code(code: u8) -> io::Result<HashFunctions> {
+    match code {
+        0 => Ok(HashFunctions::murmur64_DNA),
+        1 => Ok(HashFunctions::murmur64_protein),
+        2 => Ok(HashFunctions::murmur64_dayhoff),
+        3 => Ok(HashFunctions::murmur64_hp),
+        _ => Err(invalid("unknown hash function")),
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn write_str<W: Write>(w: &mut W, s: &str) -> io::Result<()> {
+    w.write_all(&(s.len() as u64).to_le_bytes())?;
+    w.write_all(s.as_bytes())
+}
+
+fn write_u64s<W: Write>(w: &mut W, values: &[u64]) -> io::Result<()> {
+    let mut buf = Vec::with_capacity(values.len() * 8);
+    for v in values {
+        buf.extend_from_slice(&v.to_le_bytes());
+    }
+    w.write_all(&buf)
+}
+
+fn write_cache(cache_file: &Path, key: &SourceKey, sigs: &[Signature]) -> io::Result<()> {
+    let tmp = cache_file.with_extension(format!(
+        "tmp.{}.{}",
+        std::process::id(),
+        TMP_COUNTER.fetch_add(1, Ordering::SeqCst)
+    ));
+    let written = write_cache_to(&tmp, key, sigs).and_then(|_| std::fs::rename(&tmp, cache_file));
+    if written.is_err() {
+        let _ = std::fs::remove_file(&tmp);
+    }
+    written
+}
+
+fn write_cache_to(tmp: &Path, key: &SourceKey, sigs: &[Signature]) -> io::Result<()> {
+    let mut w = BufWriter::new(File::create(tmp)?);
+
+    w.write_all(MAGIC)?;
+    write_str(&mut w, &key.path)?;
+    w.write_all(&key.size.to_le_bytes())?;
+    w.write_all(&key.mtime_secs.to_le_bytes())?;
+    w.write_all(&key.mtime_nanos.to_le_bytes())?;
+
+    w.write_all(&(sigs.len() as u64).to_le_bytes())?;
+    for sig in sigs {
+        write_str(&mut w, &sig.name())?;
+        write_str(&mut w, &sig.filename())?;
+
+        let minhashes: Vec<KmerMinHash> = sig
+            .sketches()
+            .into_iter()
+            .filter_map(|sketch| match sketch {
+                Sketch::MinHash(mh) => Some(mh),
+                _ => None,
+            })
+            .collect();
+        w.write_all(&(minhashes.len() as u64).to_le_bytes())?;
+        for mh in minhashes {
+            w.write_all(&(mh.ksize() as u32).to_le_bytes())?;
+            w.write_all(&[hash_function_code(mh.hash_function())])?;
+            w.write_all(&mh.seed().to_le_bytes())?;
+            w.write_all(&mh.max_hash().to_le_bytes())?;
+            w.write_all(&mh.num().to_le_bytes())?;
+            let abunds = mh.abunds();
+            w.write_all(&[abunds.is_some() as u8])?;
+            let mins = mh.mins();
+            w.write_all(&(mins.len() as u64).to_le_bytes())?;
+            write_u64s(&mut w, &mins)?;
+            if let Some(abunds) = abunds {
+                write_u64s(&mut w, &abunds)?;
+            }
+        }
+    }
+    w.flush()
+}
+
+/// A cursor over a cache file read into memory.
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        if self.buf.len() < n {
+            return Err(invalid("truncated cache file"));
+        }
+        let (head, tail) = self.buf.split_at(n);
+        self.buf = tail;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn len(&mut self) -> io::Result<usize> {
+        let n = self.u64()? as usize;
+        if n > self.buf.len() {
+            return Err(invalid("truncated cache file"));
+        }
+        Ok(n)
+    }
+
+    fn string(&mut self) -> io::Result<String> {
+        let n = self.len()?;
+        String::from_utf8(self.take(n)?.to_vec()).map_err(|_| invalid("bad string"))
+    }
+
+    fn u64s(&mut self, n: usize) -> io::Result<Vec<u64>> {
+        let bytes = self.take(n.checked_mul(8).ok_or_else(|| invalid("bad length"))?)?;
+        Ok(bytes
+            .chunks_exact(8)
+            .map(|b| u64::from_le_bytes(b.try_into().unwrap()))
+            .collect())
+    }
+}
+
+/// Read a cache file, returning `None` if it is missing or stale.
+fn read_cache(cache_file: &Path, key: &SourceKey) -> io::Result<Option<Vec<Signature>>> {
+    let data = match std::fs::read(cache_file) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let mut r = Reader { buf: &data };
+
+    if r.take(MAGIC.len())? != MAGIC {
+        return Err(invalid("not a sketch cache file"));
+    }
+    let cached = SourceKey {
+        path: r.string()?,
+        size: r.u64()?,
+        mtime_secs: r.u64()?,
+        mtime_nanos: r.u32()?,
+    };
+    if cached != *key {
+        debug!("{} is stale", cache_file.display());
+        return Ok(None);
+    }
+
+    let n_sigs = r.u64()?;
+    let mut sigs = vec![];
+    for _ in 0..n_sigs {
+        let name = r.string()?;
+        let filename = r.string()?;
+
+        let n_sketches = r.u64()?;
+        let mut sketches = vec![];
+        for _ in 0..n_sketches {
+            let ksize = r.u32()?;
+            let hash_function = hash_function_from_code(r.u8()?)?;
+            let seed = r.u64()?;
+            let max_hash = r.u64()?;
+            let num = r.u32()?;
+            let track_abundance = r.u8()? != 0;
+            let n_hashes = r.len()?;
+            let mins = r.u64s(n_hashes)?;
+
+            let scaled = if max_hash == 0 {
+                0
+            } else {
+                scaled_for_max_hash(max_hash)
+            };
+            let mut mh = KmerMinHash::new(scaled, ksize, hash_function, seed, track_abundance, num);
+            if mh.max_hash() != max_hash {
+                return Err(invalid("max_hash does not round-trip"));
+            }
+            if track_abundance {
+                let abunds = r.u64s(n_hashes)?;
+                let pairs: Vec<(u64, u64)> = mins.into_iter().zip(abunds).collect();
+                mh.add_many_with_abund(&pairs)
+                    .map_err(|e| invalid(&e.to_string()))?;
+            } else {
+                mh.add_many(&mins).map_err(|e| invalid(&e.to_string()))?;
+            }
+            sketches.push(Sketch::MinHash(mh));
+        }
+
+        sigs.push(
+            Signature::builder()
+                .hash_function("0.murmur64")
+                .name(Some(name))
+                .filename(Some(filename))
+                .signatures(sketches)
+                .build(),
+        );
+    }
+    Ok(Some(sigs))
+}
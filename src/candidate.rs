@@ -0,0 +1,212 @@
+//! The matchlist sketches gather picks from, and the hash arithmetic it
+//! scores them with.
+
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+
+use rayon::prelude::*;
+use sourmash::signature::SigsTrait;
+use sourmash::sketch::minhash::KmerMinHash;
+
+/// The display name and group given for a path in a CSV matchlist, and
+/// the matchlist it is from.
+#[derive(Clone, Debug, Default)]
+pub struct Label {
+    pub name: Option<String>,
+    pub group: Option<String>,
+    pub db: Option<String>,
+}
+
+/// A matchlist sketch that overlaps the query enough to be picked.
+#[derive(Clone)]
+pub struct Candidate {
+    pub name: String,
+    /// the matchlist file it was loaded from
+    pub filename: String,
+    pub db: Option<String>,
+    pub group: Option<String>,
+    /// the sketch as used, possibly trimmed to the query's hashes
+    pub minhash: KmerMinHash,
+    /// hashes shared with the query it was last scored against
+    pub containment: u64,
+    /// md5 of the sketch as loaded, kept through trimming and downsampling
+    /// to the query; size of the full sketch at the scaled used
+    pub md5: String,
+    /// md5 of the sketch `minhash` was downsampled from, if it was
+    pub source_md5: Option<String>,
+    pub match_size: usize,
+}
+
+impl Candidate {
+    pub fn new(name: String, minhash: KmerMinHash, containment: u64) -> Candidate {
+        Candidate {
+            name,
+            filename: String::new(),
+            db: None,
+            group: None,
+            md5: minhash.md5sum(),
+            source_md5: None,
+            match_size: minhash.size(),
+            minhash,
+            containment,
+        }
+    }
+
+    /// Record the file the match was loaded from.
+    pub fn with_filename(self, filename: String) -> Candidate {
+        Candidate { filename, ..self }
+    }
+
+    /// Rename the match and record its group and matchlist as the
+    /// matchlist says.
+    pub fn labelled(self, label: Option<&Label>) -> Candidate {
+        match label {
+            Some(label) => Candidate {
+                name: label.name.clone().unwrap_or(self.name),
+                group: label.group.clone(),
+                db: label.db.clone(),
+                ..self
+            },
+            None => self,
+        }
+    }
+
+    /// Record the sketch the match was downsampled from.
+    pub fn with_source(self, source: Option<&KmerMinHash>) -> Candidate {
+        Candidate {
+            source_md5: source.map(|mh| mh.md5sum()),
+            ..self
+        }
+    }
+
+    /// Which of two candidates with the same overlap every gather engine
+    /// picks first, the greater: the smallest name, then md5, so that the
+    /// pick doesn't depend on the order the candidates were loaded in.
+    pub fn break_tie(&self, other: &Candidate) -> Ordering {
+        other
+            .name
+            .cmp(&self.name)
+            .then_with(|| other.md5.cmp(&self.md5))
+    }
+
+    /// An order that tells apart candidates gather ranks the same, like
+    /// one sketch loaded from two files, whatever order they came in.
+    pub fn cmp_stable(&self, other: &Candidate) -> Ordering {
+        (&self.md5, &self.name, &self.filename, &self.db, &self.group).cmp(&(
+            &other.md5,
+            &other.name,
+            &other.filename,
+            &other.db,
+            &other.group,
+        ))
+    }
+
+    /// Drop the hashes that are not in the query; they can never matter
+    /// for gather, and md5/size of the full sketch are kept.
+    pub fn trim_to(&mut self, query: &KmerMinHash) {
+        let common = intersect_hashes(&self.minhash, query);
+        let mut trimmed = self.minhash.clone();
+        trimmed.clear();
+        trimmed.add_many(&common).unwrap();
+        self.minhash = trimmed;
+    }
+}
+
+/// Largest overlap first; ties as `break_tie` says.
+impl Ord for Candidate {
+    fn cmp(&self, other: &Candidate) -> Ordering {
+        self.containment
+            .cmp(&other.containment)
+            .then_with(|| self.break_tie(other))
+    }
+}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Candidate {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Candidate {}
+
+/// Rescore `sketchlist` against `query`, dropping the candidates that no
+/// longer share `threshold_hashes` with it.
+pub fn prefetch(
+    query: &KmerMinHash,
+    sketchlist: BinaryHeap<Candidate>,
+    threshold_hashes: u64,
+) -> BinaryHeap<Candidate> {
+    sketchlist
+        .into_par_iter()
+        .filter_map(|result| {
+            let mut mm = None;
+            let searchsig = &result.minhash;
+            let containment = searchsig.count_common(query, false);
+            if let Ok(containment) = containment {
+                if containment >= threshold_hashes {
+                    let result = Candidate {
+                        containment,
+                        ..result
+                    };
+                    mm = Some(result);
+                }
+            }
+            mm
+        })
+        .collect()
+}
+
+/// The hashes present in both sketches, in sorted order.
+pub fn intersect_hashes(a: &KmerMinHash, b: &KmerMinHash) -> Vec<u64> {
+    let mut common = vec![];
+    let mut a_iter = a.iter_mins().peekable();
+    let mut b_iter = b.iter_mins().peekable();
+    while let (Some(x), Some(y)) = (a_iter.peek(), b_iter.peek()) {
+        match x.cmp(y) {
+            Ordering::Less => {
+                a_iter.next();
+            }
+            Ordering::Greater => {
+                b_iter.next();
+            }
+            Ordering::Equal => {
+                common.push(**x);
+                a_iter.next();
+                b_iter.next();
+            }
+        }
+    }
+    common
+}
+
+/// Sum of the query abundances of the hashes shared with `other`.
+pub fn weighted_overlap(query: &KmerMinHash, other: &KmerMinHash) -> u64 {
+    let common = intersect_hashes(query, other);
+    let abunds = query.to_vec_abunds();
+    let mut found = 0;
+    let mut i = 0;
+    for hash in common {
+        while abunds[i].0 < hash {
+            i += 1;
+        }
+        found += abunds[i].1;
+    }
+    found
+}
+
+/// Jaccard similarity of two sketches of the given sizes that share
+/// `intersect_hashes` hashes.
+pub fn jaccard(intersect_hashes: u64, size_a: usize, size_b: usize) -> f64 {
+    let union = (size_a + size_b) as u64 - intersect_hashes;
+    if union == 0 {
+        0.0
+    } else {
+        intersect_hashes as f64 / union as f64
+    }
+}
@@ -0,0 +1,108 @@
+use std::path::PathBuf;
+
+use rayon::prelude::*;
+
+use sourmash::signature::Signature;
+
+use crate::GatherArgs;
+use crate::{
+    build_template, is_database, load_matchlists, load_query_sigs, prepare_query,
+    prepare_query_any_scaled, select_query,
+};
+
+enum CheckStatus {
+    Ok,
+    Unreadable(String),
+    Incompatible(String),
+}
+
+/// Validate that the query has a usable sketch and that every matchlist
+/// entry can be read and has a sketch compatible with the template.
+///
+/// No containment is computed, so this is cheap even for big matchlists.
+pub fn check(args: &GatherArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let query_filename = match (&args.query, &args.query_list) {
+        (Some(query), None) => query,
+        _ => return Err("check needs a query and a matchlist, not --query-list".into()),
+    };
+
+    let sigs = load_query_sigs(query_filename, args)?;
+    let template = build_template(
+        &sigs,
+        args.template.ksize,
+        args.template.scaled,
+        args.template.moltype()?,
+        args.template.seed,
+    )?;
+    select_query(&sigs, &template)?;
+    println!("query OK: {}", query_filename.display());
+
+    let mut lists = vec![];
+    for matchlist in args.matchlist.iter().chain(&args.db) {
+        if is_database(matchlist) {
+            println!("database, not checked: {}", matchlist.display());
+        } else {
+            lists.push(matchlist);
+        }
+    }
+    let matchlist_paths = load_matchlists(&lists)?.paths;
+
+    let statuses: Vec<(&PathBuf, CheckStatus)> = matchlist_paths
+        .par_iter()
+        .map(|path| {
+            let status = match Signature::from_path(path) {
+                Err(e) => CheckStatus::Unreadable(e.to_string()),
+                Ok(sigs) => {
+                    let mut status = CheckStatus::Incompatible("no signatures".into());
+                    for (i, sig) in sigs.iter().enumerate() {
+                        let prepared = if args.auto_downsample {
+                            prepare_query_any_scaled(sig, &template)
+                        } else {
+                            prepare_query(sig, &template)
+                        };
+                        match prepared {
+                            Ok(_) => {
+                                status = CheckStatus::Ok;
+                                break;
+                            }
+                            Err(e) if i == 0 => status = CheckStatus::Incompatible(e.to_string()),
+                            Err(_) => {}
+                        }
+                    }
+                    status
+                }
+            };
+            (path, status)
+        })
+        .collect();
+
+    let mut n_ok = 0;
+    let mut n_unreadable = 0;
+    let mut n_incompatible = 0;
+    for (path, status) in &statuses {
+        match status {
+            CheckStatus::Ok => n_ok += 1,
+            CheckStatus::Unreadable(e) => {
+                n_unreadable += 1;
+                println!("unreadable: {} ({})", path.display(), e);
+            }
+            CheckStatus::Incompatible(e) => {
+                n_incompatible += 1;
+                println!("no compatible sketch: {} ({})", path.display(), e);
+            }
+        }
+    }
+
+    println!(
+        "checked {} matchlist entries: {} OK, {} unreadable, {} without a compatible sketch",
+        statuses.len(),
+        n_ok,
+        n_unreadable,
+        n_incompatible
+    );
+
+    if n_ok != statuses.len() {
+        return Err(format!("{} matchlist entries failed checks", statuses.len() - n_ok).into());
+    }
+    Ok(())
+}
@@ -0,0 +1,129 @@
+//! Greedy clustering of a collection of signatures by containment.
+
+use std::io::Write;
+use std::path::PathBuf;
+
+use clap::Args;
+use log::info;
+use rayon::prelude::*;
+use serde::Serialize;
+
+use crate::collection::CollectionArgs;
+use crate::{ksize_of, output};
+
+#[derive(Args, Debug)]
+pub struct ClusterArgs {
+    #[clap(flatten)]
+    sigs: CollectionArgs,
+
+    /// minimum similarity to a cluster's representative to join it
+    #[clap(long = "threshold", default_value = "0.9")]
+    threshold: f64,
+
+    /// compare by containment in the representative, or by ANI estimated
+    /// from it
+    #[clap(
+        long = "metric",
+        default_value = "containment",
+        possible_values = &["containment", "ani"]
+    )]
+    metric: String,
+
+    /// write the cluster of every signature to this CSV file
+    #[clap(short = 'o', long = "output", parse(from_os_str))]
+    output: PathBuf,
+
+    /// write the files of the cluster representatives to this list, usable
+    /// as a matchlist
+    #[clap(long = "representatives", parse(from_os_str))]
+    representatives: Option<PathBuf>,
+}
+
+#[derive(Serialize)]
+struct ClusterRow<'a> {
+    cluster: usize,
+    name: &'a str,
+    md5: &'a str,
+    representative: &'a str,
+    is_representative: bool,
+    containment: f64,
+    ani: f64,
+}
+
+pub fn cluster(args: &ClusterArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let sketches = args.sigs.load()?;
+
+    // the largest remaining sketch becomes the next representative.
+    let mut order: Vec<usize> = (0..sketches.len()).collect();
+    order.sort_by(|a, b| {
+        sketches[*b]
+            .1
+            .match_size
+            .cmp(&sketches[*a].1.match_size)
+            .then_with(|| a.cmp(b))
+    });
+
+    // (cluster, representative, containment in the representative)
+    let mut assignment: Vec<Option<(usize, usize, f64)>> = vec![None; sketches.len()];
+    let mut representatives = vec![];
+    for &rep in &order {
+        if assignment[rep].is_some() {
+            continue;
+        }
+        let cluster = representatives.len();
+        representatives.push(rep);
+        assignment[rep] = Some((cluster, rep, 1.0));
+
+        let rep_mh = &sketches[rep].1.minhash;
+        let ksize = ksize_of(rep_mh) as f64;
+        let members: Vec<(usize, f64)> = order
+            .par_iter()
+            .filter(|&&i| assignment[i].is_none())
+            .filter_map(|&i| {
+                let member = &sketches[i].1;
+                let common = member.minhash.count_common(rep_mh, false).ok()?;
+                let containment = common as f64 / member.match_size as f64;
+                let similarity = match args.metric.as_str() {
+                    "ani" => containment.powf(1.0 / ksize),
+                    _ => containment,
+                };
+                (similarity >= args.threshold).then_some((i, containment))
+            })
+            .collect();
+        for (i, containment) in members {
+            assignment[i] = Some((cluster, rep, containment));
+        }
+    }
+
+    let mut writer = output::create_csv(&args.output)?;
+    for (i, (_, sketch)) in sketches.iter().enumerate() {
+        let (cluster, rep, containment) = assignment[i].unwrap();
+        let ksize = ksize_of(&sketch.minhash) as f64;
+        writer.serialize(ClusterRow {
+            cluster,
+            name: &sketch.name,
+            md5: &sketch.md5,
+            representative: &sketches[rep].1.name,
+            is_representative: i == rep,
+            containment,
+            ani: containment.powf(1.0 / ksize),
+        })?;
+    }
+    output::finish_csv(writer)?;
+    info!(
+        "Wrote {} clusters of {} signatures to {}",
+        representatives.len(),
+        sketches.len(),
+        args.output.display()
+    );
+
+    if let Some(path) = &args.representatives {
+        let mut out = output::create(path)?;
+        for rep in &representatives {
+            writeln!(out, "{}", sketches[*rep].0.display())?;
+        }
+        out.finish()?;
+        info!("Wrote representatives to {}", path.display());
+    }
+    Ok(())
+}
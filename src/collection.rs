@@ -0,0 +1,71 @@
+//! Arguments and loading shared by the subcommands that work on a whole
+//! collection of signatures rather than a query and a matchlist.
+
+use std::path::PathBuf;
+
+use clap::Args;
+use log::info;
+use rayon::prelude::*;
+
+use sourmash::encodings::HashFunctions;
+use sourmash::sketch::Sketch;
+
+use crate::{compute, serve, Candidate};
+
+#[derive(Args, Debug)]
+pub struct CollectionArgs {
+    /// file listing signature files, or directory of signature files
+    #[clap(parse(from_os_str))]
+    siglist: PathBuf,
+
+    /// k-mer size
+    #[clap(short = 'k', long = "ksize", default_value = "31")]
+    ksize: u32,
+
+    /// scaled value
+    #[clap(short = 's', long = "scaled", default_value = "1000")]
+    scaled: u64,
+
+    /// molecule type
+    #[clap(
+        long = "moltype",
+        default_value = "DNA",
+        possible_values = &["DNA", "protein", "dayhoff", "hp"]
+    )]
+    moltype: String,
+
+    /// hash seed
+    #[clap(long = "seed", default_value = "42")]
+    seed: u64,
+}
+
+impl CollectionArgs {
+    /// Load every sketch compatible with the template, in siglist order,
+    /// along with the file it came from.
+    pub(crate) fn load(&self) -> Result<Vec<(PathBuf, Candidate)>, Box<dyn std::error::Error>> {
+        let hash_function = HashFunctions::try_from(self.moltype.as_str())?;
+        let template = Sketch::MinHash(compute::empty_minhash(
+            self.ksize,
+            self.scaled,
+            hash_function,
+            self.seed,
+            false,
+        ));
+
+        let paths = serve::db_paths(&self.siglist)?;
+        let sketches: Vec<(PathBuf, Candidate)> = paths
+            .par_iter()
+            .flat_map_iter(|path| {
+                serve::load_db(std::slice::from_ref(path), &template)
+                    .into_iter()
+                    .map(move |sketch| (path.clone(), sketch))
+            })
+            .collect();
+        info!(
+            "Loaded {} sketches from {} files",
+            sketches.len(),
+            paths.len()
+        );
+        Ok(sketches)
+    }
+}
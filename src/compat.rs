@@ -0,0 +1,191 @@
+//! `--compat sourmash`: gather CSV rows laid out like `sourmash gather`
+//! (4.x) writes them, so that tools parsing its output can read ours.
+//!
+//! Floats are formatted the way Python prints them, and booleans as
+//! `True`/`False`. The ANI columns are point estimates, and
+//! `potential_false_negative` is always `False`.
+
+use serde::Serialize;
+use sourmash::encodings::HashFunctions;
+use sourmash::signature::SigsTrait;
+use sourmash::sketch::minhash::KmerMinHash;
+
+use crate::{intersect_hashes, ksize_of, Candidate, GatherResult};
+
+/// A float as Python's `repr` would print it.
+fn py_float(x: f64) -> String {
+    if x.is_nan() {
+        return "nan".into();
+    }
+    if x.is_infinite() {
+        return if x > 0.0 { "inf" } else { "-inf" }.into();
+    }
+    let sci = format!("{:e}", x);
+    let (mantissa, exp) = sci.split_once('e').unwrap();
+    let exp: i32 = exp.parse().unwrap();
+    if x != 0.0 && !(-4..16).contains(&exp) {
+        format!(
+            "{}e{}{:02}",
+            mantissa,
+            if exp < 0 { '-' } else { '+' },
+            exp.abs()
+        )
+    } else {
+        let plain = format!("{}", x);
+        if plain.contains('.') {
+            plain
+        } else {
+            plain + ".0"
+        }
+    }
+}
+
+fn py_bool(b: bool) -> &'static str {
+    if b {
+        "True"
+    } else {
+        "False"
+    }
+}
+
+pub(crate) fn moltype_name(hash_function: HashFunctions) -> &'static str {
+    match hash_function {
+        HashFunctions::murmur64_DNA => "DNA",
+        HashFunctions::murmur64_protein => "protein",
+        HashFunctions::murmur64_dayhoff => "dayhoff",
+        HashFunctions::murmur64_hp => "hp",
+    }
+}
+
+/// What every row says about the query.
+pub(crate) struct QueryInfo {
+    pub(crate) filename: String,
+    pub(crate) name: String,
+    pub(crate) abundance: bool,
+}
+
+#[derive(Serialize)]
+pub(crate) struct SourmashRow<'a> {
+    intersect_bp: u64,
+    f_orig_query: String,
+    f_match: String,
+    f_unique_to_query: String,
+    f_unique_weighted: String,
+    average_abund: String,
+    median_abund: String,
+    std_abund: String,
+    filename: String,
+    name: &'a str,
+    md5: &'a str,
+    f_match_orig: String,
+    unique_intersect_bp: u64,
+    gather_result_rank: usize,
+    remaining_bp: u64,
+    query_filename: &'a str,
+    query_name: &'a str,
+    query_md5: String,
+    query_bp: u64,
+    ksize: u32,
+    moltype: &'static str,
+    scaled: u64,
+    query_n_hashes: usize,
+    query_abundance: &'static str,
+    query_containment_ani: String,
+    match_containment_ani: String,
+    average_containment_ani: String,
+    max_containment_ani: String,
+    potential_false_negative: &'static str,
+    n_unique_weighted_found: u64,
+    pub(crate) sum_weighted_found: u64,
+    total_weighted_hashes: u64,
+}
+
+impl<'a> SourmashRow<'a> {
+    /// The row for `result`, with `query` the query before `best` was
+    /// removed from it and `weighted_found_before` the abundance-weighted
+    /// hashes claimed by earlier rounds.
+    pub(crate) fn new(
+        result: &'a GatherResult,
+        best: &Candidate,
+        query: &KmerMinHash,
+        orig_query: &KmerMinHash,
+        info: &'a QueryInfo,
+        weighted_found_before: u64,
+    ) -> SourmashRow<'a> {
+        // abundances of the query hashes this match claimed; 1 each
+        // without abundances.
+        let query_abunds = query.to_vec_abunds();
+        let abunds: Vec<u64> = intersect_hashes(query, &best.minhash)
+            .into_iter()
+            .filter_map(|hash| {
+                query_abunds
+                    .binary_search_by_key(&hash, |(h, _)| *h)
+                    .ok()
+                    .map(|i| query_abunds[i].1)
+            })
+            .collect();
+        let n_unique_weighted_found: u64 = abunds.iter().sum();
+        let total_weighted_hashes: u64 = orig_query.to_vec_abunds().iter().map(|(_, a)| a).sum();
+
+        let n = abunds.len().max(1) as f64;
+        let average_abund = n_unique_weighted_found as f64 / n;
+        let variance = abunds
+            .iter()
+            .map(|a| (*a as f64 - average_abund).powi(2))
+            .sum::<f64>()
+            / n;
+        let mut sorted = abunds.clone();
+        sorted.sort_unstable();
+        let median_abund = match sorted.len() {
+            0 => 0.0,
+            len if len % 2 == 1 => sorted[len / 2] as f64,
+            len => (sorted[len / 2 - 1] + sorted[len / 2]) as f64 / 2.0,
+        };
+
+        let hash_function = orig_query.hash_function();
+        let ksize = ksize_of(orig_query);
+        let scaled = orig_query.scaled();
+        let match_size = best.match_size as f64;
+        let f_match = best.containment as f64 / match_size;
+        let f_match_orig = result.intersect_bp as f64 / scaled as f64 / match_size;
+        let query_ani = result.f_orig_query.powf(1.0 / ksize as f64);
+        let match_ani = f_match_orig.powf(1.0 / ksize as f64);
+
+        SourmashRow {
+            intersect_bp: result.intersect_bp,
+            f_orig_query: py_float(result.f_orig_query),
+            f_match: py_float(f_match),
+            f_unique_to_query: py_float(result.f_unique_to_query),
+            f_unique_weighted: py_float(
+                n_unique_weighted_found as f64 / total_weighted_hashes as f64,
+            ),
+            average_abund: py_float(average_abund),
+            median_abund: py_float(median_abund),
+            std_abund: py_float(variance.sqrt()),
+            filename: best.db.clone().unwrap_or_default(),
+            name: &result.name,
+            md5: &result.md5,
+            f_match_orig: py_float(f_match_orig),
+            unique_intersect_bp: result.unique_intersect_bp,
+            gather_result_rank: result.gather_rank,
+            remaining_bp: result.remaining_bp,
+            query_filename: &info.filename,
+            query_name: &info.name,
+            query_md5: result.query_md5[..8].to_string(),
+            query_bp: orig_query.size() as u64 * scaled,
+            ksize,
+            moltype: moltype_name(hash_function),
+            scaled,
+            query_n_hashes: orig_query.size(),
+            query_abundance: py_bool(info.abundance),
+            query_containment_ani: py_float(query_ani),
+            match_containment_ani: py_float(match_ani),
+            average_containment_ani: py_float((query_ani + match_ani) / 2.0),
+            max_containment_ani: py_float(query_ani.max(match_ani)),
+            potential_false_negative: py_bool(false),
+            n_unique_weighted_found,
+            sum_weighted_found: weighted_found_before + n_unique_weighted_found,
+            total_weighted_hashes,
+        }
+    }
+}
@@ -0,0 +1,168 @@
+use std::path::{Path, PathBuf};
+
+use clap::Args;
+use log::info;
+use rayon::prelude::*;
+
+use sourmash::encodings::HashFunctions;
+use sourmash::signature::{Signature, SigsTrait};
+use sourmash::sketch::minhash::KmerMinHash;
+use sourmash::sketch::Sketch;
+
+use crate::output;
+
+#[derive(Args, Debug)]
+pub struct SketchArgs {
+    /// FASTA/FASTQ files (optionally gzipped) to sketch
+    #[clap(parse(from_os_str), required = true)]
+    inputs: Vec<PathBuf>,
+
+    /// output signature file
+    #[clap(short = 'o', long = "output", parse(from_os_str))]
+    output: PathBuf,
+
+    /// k-mer size
+    #[clap(short = 'k', long = "ksize", default_value = "31")]
+    ksize: u32,
+
+    /// scaled value
+    #[clap(short = 's', long = "scaled", default_value = "1000")]
+    scaled: u64,
+
+    /// molecule type
+    #[clap(
+        long = "moltype",
+        default_value = "DNA",
+        possible_values = &["DNA", "protein", "dayhoff", "hp"]
+    )]
+    moltype: String,
+
+    /// hash seed
+    #[clap(long = "seed", default_value = "42")]
+    seed: u64,
+
+    /// track k-mer abundances
+    #[clap(long = "track-abundance")]
+    track_abundance: bool,
+
+    /// input sequences are protein rather than DNA
+    #[clap(long = "input-is-protein")]
+    input_is_protein: bool,
+}
+
+/// Build an empty scaled sketch with the given parameters.
+///
+/// `ksize` is the user-facing k-mer size; protein-ish ksizes are stored
+/// multiplied by 3 in the sketch.
+pub fn empty_minhash(
+    ksize: u32,
+    scaled: u64,
+    hash_function: HashFunctions,
+    seed: u64,
+    track_abundance: bool,
+) -> KmerMinHash {
+    let ksize = match hash_function {
+        HashFunctions::murmur64_DNA => ksize,
+        _ => ksize * 3,
+    };
+    KmerMinHash::new(scaled, ksize, hash_function, seed, track_abundance, 0)
+}
+
+/// Sketch all sequences in a FASTA/FASTQ file into a copy of `template`.
+pub fn sketch_file<P: AsRef<Path>>(
+    path: P,
+    template: &KmerMinHash,
+    input_is_protein: bool,
+) -> Result<Signature, Box<dyn std::error::Error>> {
+    let path = path.as_ref();
+    let mut mh = template.clone();
+    mh.clear();
+
+    let mut reader = needletail::parse_fastx_file(path)?;
+    while let Some(record) = reader.next() {
+        let record = record?;
+        if input_is_protein {
+            mh.add_protein(&record.seq())?;
+        } else {
+            // skip k-mers with invalid characters rather than failing.
+            mh.add_sequence(&record.seq(), true)?;
+        }
+    }
+
+    let filename = path.display().to_string();
+    let sig = Signature::builder()
+        .hash_function("0.murmur64")
+        .name(Some(filename.clone()))
+        .filename(Some(filename))
+        .signatures(vec![Sketch::MinHash(mh)])
+        .build();
+    Ok(sig)
+}
+
+/// Sketch every record in a FASTA/FASTQ file on its own, into signatures
+/// named after the record headers.
+pub fn sketch_records<P: AsRef<Path>>(
+    path: P,
+    template: &KmerMinHash,
+    input_is_protein: bool,
+) -> Result<Vec<Signature>, Box<dyn std::error::Error>> {
+    let path = path.as_ref();
+    let filename = path.display().to_string();
+
+    let mut sigs = vec![];
+    let mut reader = needletail::parse_fastx_file(path)?;
+    while let Some(record) = reader.next() {
+        let record = record?;
+        let mut mh = template.clone();
+        mh.clear();
+        if input_is_protein {
+            mh.add_protein(&record.seq())?;
+        } else {
+            mh.add_sequence(&record.seq(), true)?;
+        }
+        let sig = Signature::builder()
+            .hash_function("0.murmur64")
+            .name(Some(String::from_utf8_lossy(record.id()).into_owned()))
+            .filename(Some(filename.clone()))
+            .signatures(vec![Sketch::MinHash(mh)])
+            .build();
+        sigs.push(sig);
+    }
+    Ok(sigs)
+}
+
+/// Sketch each input file and save all signatures to a single file.
+pub fn sketch(args: &SketchArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let hash_function = HashFunctions::try_from(args.moltype.as_str())?;
+    if args.input_is_protein && hash_function.dna() {
+        return Err("--input-is-protein requires a protein, dayhoff or hp moltype".into());
+    }
+    let template = empty_minhash(
+        args.ksize,
+        args.scaled,
+        hash_function,
+        args.seed,
+        args.track_abundance,
+    );
+
+    info!("Sketching {} files", args.inputs.len());
+    let sigs: Vec<Signature> = args
+        .inputs
+        .par_iter()
+        .map(|path| {
+            sketch_file(path, &template, args.input_is_protein)
+                .map_err(|e| format!("error sketching {}: {}", path.display(), e))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut out = output::create(&args.output)?;
+    serde_json::to_writer(&mut out, &sigs)?;
+    out.finish()?;
+    info!(
+        "Wrote {} signatures to {}",
+        sigs.len(),
+        args.output.display()
+    );
+
+    Ok(())
+}
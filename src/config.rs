@@ -0,0 +1,139 @@
+//! Default gather parameters from a TOML file, so a lab can share one set
+//! of settings. Values given on the command line or through the
+//! `COUNTERGATHER_*` environment variables take precedence.
+//!
+//! ```toml
+//! ksize = 31
+//! scaled = 1000
+//! threshold-bp = 50000
+//! threads = 8
+//! report = true
+//! gzip-output = true
+//! compat = "sourmash"
+//! ```
+
+use std::path::{Path, PathBuf};
+
+use clap::parser::ValueSource;
+use clap::ArgMatches;
+use log::info;
+use serde::Deserialize;
+
+use crate::GatherArgs;
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub(crate) struct Config {
+    ksize: Option<u32>,
+    scaled: Option<u64>,
+    moltype: Option<String>,
+    seed: Option<u64>,
+    threshold_bp: Option<u64>,
+    min_unique_bp: Option<u64>,
+    stop_at_fraction: Option<f64>,
+    rank_by: Option<String>,
+    threads: Option<usize>,
+    report: Option<bool>,
+    gzip_output: Option<bool>,
+    /// the `--compat` layout of the -o CSV
+    compat: Option<String>,
+}
+
+/// `$XDG_CONFIG_HOME/countergather/config.toml`, falling back to
+/// `~/.config/countergather/config.toml`.
+fn default_path() -> Option<PathBuf> {
+    let base = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+    };
+    Some(base.join("countergather").join("config.toml"))
+}
+
+fn check_value(key: &str, value: &str, allowed: &[&str]) -> Result<(), String> {
+    if allowed.contains(&value) {
+        Ok(())
+    } else {
+        Err(format!(
+            "config: {} must be one of {}, not {}",
+            key,
+            allowed.join(", "),
+            value
+        ))
+    }
+}
+
+impl Config {
+    /// Load the given config file, or the default one if it exists.
+    pub(crate) fn load(path: Option<&Path>) -> Result<Config, Box<dyn std::error::Error>> {
+        let path = match path {
+            Some(path) => path.to_path_buf(),
+            None => match default_path() {
+                Some(path) if path.is_file() => path,
+                _ => return Ok(Config::default()),
+            },
+        };
+        let text = std::fs::read_to_string(&path)
+            .map_err(|e| format!("could not read config {}: {}", path.display(), e))?;
+        let config: Config =
+            toml::from_str(&text).map_err(|e| format!("{}: {}", path.display(), e))?;
+
+        if let Some(moltype) = &config.moltype {
+            check_value("moltype", moltype, &["DNA", "protein", "dayhoff", "hp"])?;
+        }
+        if let Some(rank_by) = &config.rank_by {
+            check_value("rank-by", rank_by, &["overlap", "containment", "weighted"])?;
+        }
+        if let Some(compat) = &config.compat {
+            check_value("compat", compat, &["sourmash"])?;
+        }
+        info!("Using config {}", path.display());
+        Ok(config)
+    }
+
+    /// Fill in the arguments that weren't given on the command line.
+    pub(crate) fn apply(&self, args: &mut GatherArgs, matches: &ArgMatches) {
+        let unset = |id: &str| {
+            matches!(
+                matches.value_source(id),
+                None | Some(ValueSource::DefaultValue)
+            )
+        };
+
+        if let (Some(ksize), true) = (self.ksize, unset("ksize")) {
+            args.template.ksize = Some(ksize);
+        }
+        if let (Some(scaled), true) = (self.scaled, unset("scaled")) {
+            args.template.scaled = Some(scaled);
+        }
+        if let (Some(moltype), true) = (&self.moltype, unset("moltype")) {
+            args.template.moltype = Some(moltype.clone());
+        }
+        if let (Some(seed), true) = (self.seed, unset("seed")) {
+            args.template.seed = seed;
+        }
+        if let (Some(threshold_bp), true) = (self.threshold_bp, unset("threshold-bp")) {
+            args.threshold_bp = threshold_bp;
+        }
+        if let (Some(min_unique_bp), true) = (self.min_unique_bp, unset("min-unique-bp")) {
+            args.min_unique_bp = Some(min_unique_bp);
+        }
+        if let (Some(stop_at), true) = (self.stop_at_fraction, unset("stop-at-fraction")) {
+            args.stop_at_fraction = Some(stop_at);
+        }
+        if let (Some(rank_by), true) = (&self.rank_by, unset("rank-by")) {
+            args.rank_by = rank_by.clone();
+        }
+        if let (Some(threads), true) = (self.threads, unset("threads")) {
+            args.threads = Some(threads);
+        }
+        if let (Some(report), true) = (self.report, unset("report")) {
+            args.report = report;
+        }
+        if let (Some(gzip_output), true) = (self.gzip_output, unset("gzip-output")) {
+            args.gzip_output = gzip_output;
+        }
+        if let (Some(compat), true) = (&self.compat, unset("compat")) {
+            args.compat = Some(compat.clone());
+        }
+    }
+}
@@ -0,0 +1,97 @@
+//! `--derep-threshold`: collapse the candidate matches that are
+//! near-identical to each other before gather, so that interchangeable
+//! assemblies of one strain don't each get a round.
+//!
+//! Candidates are visited best first, as gather would pick them; each one
+//! not yet collapsed becomes a representative, and takes every remaining
+//! candidate whose containment ANI with it is at least the threshold in
+//! both directions.
+
+use std::collections::BinaryHeap;
+use std::path::Path;
+
+use log::info;
+use rayon::prelude::*;
+use serde::Serialize;
+
+use crate::{ksize_of, output, Candidate};
+
+/// A candidate and the representative it was collapsed into.
+#[derive(Serialize)]
+pub(crate) struct DerepRow {
+    pub(crate) representative: String,
+    pub(crate) representative_md5: String,
+    name: String,
+    md5: String,
+    ani: f64,
+}
+
+/// The smaller of the two containment ANIs between `a` and `b`, using the
+/// ksize in amino acids for protein-ish sketches.
+fn mutual_ani(a: &Candidate, b: &Candidate) -> f64 {
+    let common = a.minhash.count_common(&b.minhash, false).unwrap_or(0) as f64;
+    let ksize = ksize_of(&a.minhash) as f64;
+    let containment = common / a.match_size.max(b.match_size) as f64;
+    containment.powf(1.0 / ksize)
+}
+
+/// Keep one representative of every group of candidates within
+/// `min_ani` of each other, and say which candidate went where.
+pub(crate) fn dereplicate(
+    candidates: BinaryHeap<Candidate>,
+    min_ani: f64,
+) -> (BinaryHeap<Candidate>, Vec<DerepRow>) {
+    let mut remaining = candidates.into_sorted_vec();
+    remaining.reverse();
+    let n_candidates = remaining.len();
+
+    let mut representatives = vec![];
+    let mut rows = vec![];
+    while !remaining.is_empty() {
+        let rep = remaining.remove(0);
+        let anis: Vec<f64> = remaining
+            .par_iter()
+            .map(|candidate| mutual_ani(&rep, candidate))
+            .collect();
+        rows.push(DerepRow {
+            representative: rep.name.clone(),
+            representative_md5: rep.md5.clone(),
+            name: rep.name.clone(),
+            md5: rep.md5.clone(),
+            ani: 1.0,
+        });
+
+        let mut rest = vec![];
+        for (candidate, ani) in remaining.into_iter().zip(anis) {
+            if ani >= min_ani {
+                rows.push(DerepRow {
+                    representative: rep.name.clone(),
+                    representative_md5: rep.md5.clone(),
+                    name: candidate.name,
+                    md5: candidate.md5,
+                    ani,
+                });
+            } else {
+                rest.push(candidate);
+            }
+        }
+        remaining = rest;
+        representatives.push(rep);
+    }
+
+    info!(
+        "Dereplicated {} candidates into {} representatives",
+        n_candidates,
+        representatives.len()
+    );
+    (representatives.into(), rows)
+}
+
+pub(crate) fn write_derep_csv(path: &Path, rows: &[DerepRow]) -> Result<(), csv::Error> {
+    let mut writer = output::create_csv(path)?;
+    for row in rows {
+        writer.serialize(row)?;
+    }
+    output::finish_csv(writer)?;
+    Ok(())
+}
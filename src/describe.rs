@@ -0,0 +1,48 @@
+use std::path::Path;
+
+use sourmash::signature::{Signature, SigsTrait};
+use sourmash::sketch::Sketch;
+
+use crate::ksize_of;
+
+/// Print the metadata of every sketch in a signature file.
+pub fn describe<P: AsRef<Path>>(path: P) -> Result<(), Box<dyn std::error::Error>> {
+    let sigs = Signature::from_path(path.as_ref())?;
+
+    println!("{}: {} signature(s)", path.as_ref().display(), sigs.len());
+    for sig in &sigs {
+        for sketch in sig.sketches() {
+            println!("---");
+            println!("name: {}", sig.name());
+            println!("filename: {}", sig.filename());
+            match sketch {
+                Sketch::MinHash(mh) => {
+                    let ksize = ksize_of(&mh);
+                    println!("md5: {}", mh.md5sum());
+                    println!("ksize: {}", ksize);
+                    if mh.num() == 0 {
+                        println!("scaled: {}", mh.scaled());
+                    } else {
+                        println!("num: {}", mh.num());
+                    }
+                    println!("moltype: {}", mh.hash_function());
+                    println!("seed: {}", mh.seed());
+                    println!("abundance: {}", mh.track_abundance());
+                    println!("hashes: {}", mh.size());
+                }
+                Sketch::LargeMinHash(mh) => {
+                    println!("md5: {}", mh.md5sum());
+                    println!("ksize: {}", mh.ksize());
+                    println!("moltype: {}", mh.hash_function());
+                    println!("seed: {}", mh.seed());
+                    println!("hashes: {}", mh.size());
+                }
+                Sketch::HyperLogLog(_) => {
+                    println!("sketch type: HyperLogLog (unsupported)");
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,376 @@
+//! Finding the sketch of a signature that fits a template, downsampling
+//! it to the template's scaled if it is finer.
+
+use std::fmt;
+
+use sourmash::encodings::HashFunctions;
+use sourmash::signature::{Signature, SigsTrait};
+use sourmash::sketch::minhash::{max_hash_for_scaled, KmerMinHash};
+use sourmash::sketch::Sketch;
+
+/// Why a sketch can't be downsampled into another one's parameters.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CompatibilityError {
+    /// num sketches can only be compared with the same num; 0 is scaled
+    Num {
+        me: u32,
+        other: u32,
+    },
+    /// k-mer sizes as stored, i.e. 3 times the protein k-mer size
+    KSize {
+        me: u32,
+        other: u32,
+    },
+    HashFunction {
+        me: HashFunctions,
+        other: HashFunctions,
+    },
+    /// a sketch can be downsampled to a coarser scaled, but not a finer one
+    Scaled {
+        me: u64,
+        other: u64,
+    },
+    Seed {
+        me: u64,
+        other: u64,
+    },
+    /// compatible, but sourmash failed to downsample it to `scaled`
+    Downsample {
+        scaled: u64,
+        reason: String,
+    },
+}
+
+impl fmt::Display for CompatibilityError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CompatibilityError::Num { me, other } => {
+                write!(f, "num {} does not match num {}", me, other)
+            }
+            CompatibilityError::KSize { me, other } => {
+                write!(f, "k-mer size {} does not match {}", me, other)
+            }
+            CompatibilityError::HashFunction { me, other } => {
+                write!(f, "moltype {} does not match {}", me, other)
+            }
+            CompatibilityError::Scaled { me, other } => {
+                write!(f, "scaled {} is coarser than {}", me, other)
+            }
+            CompatibilityError::Seed { me, other } => {
+                write!(f, "seed {} does not match {}", me, other)
+            }
+            CompatibilityError::Downsample { scaled, reason } => {
+                write!(f, "could not downsample to scaled {}: {}", scaled, reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for CompatibilityError {}
+
+/// Why `prepare_query` found no sketch.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PrepareQueryError {
+    /// only MinHash templates are supported
+    TemplateNotMinHash,
+    /// why each MinHash sketch of the signature didn't fit, in order
+    NoCompatibleSketch(Vec<CompatibilityError>),
+}
+
+impl fmt::Display for PrepareQueryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PrepareQueryError::TemplateNotMinHash => write!(f, "the template is not a MinHash"),
+            PrepareQueryError::NoCompatibleSketch(reasons) if reasons.is_empty() => {
+                write!(f, "the signature has no MinHash sketches")
+            }
+            PrepareQueryError::NoCompatibleSketch(reasons) => {
+                write!(f, "no sketch compatible with the template: ")?;
+                for (i, reason) in reasons.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, "; ")?;
+                    }
+                    write!(f, "{}", reason)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for PrepareQueryError {}
+
+/// Check that `me` can be downsampled to compare with `other`: the same
+/// num, k-mer size, moltype and seed, and a scaled no coarser.
+pub fn check_compatible_downsample(
+    me: &KmerMinHash,
+    other: &KmerMinHash,
+) -> Result<(), CompatibilityError> {
+    if me.num() != other.num() {
+        return Err(CompatibilityError::Num {
+            me: me.num(),
+            other: other.num(),
+        });
+    }
+    if me.ksize() != other.ksize() {
+        return Err(CompatibilityError::KSize {
+            me: me.ksize() as u32,
+            other: other.ksize() as u32,
+        });
+    }
+    if me.hash_function() != other.hash_function() {
+        return Err(CompatibilityError::HashFunction {
+            me: me.hash_function(),
+            other: other.hash_function(),
+        });
+    }
+    if me.max_hash() < other.max_hash() {
+        return Err(CompatibilityError::Scaled {
+            me: me.scaled(),
+            other: other.scaled(),
+        });
+    }
+    if me.seed() != other.seed() {
+        return Err(CompatibilityError::Seed {
+            me: me.seed(),
+            other: other.seed(),
+        });
+    }
+    Ok(())
+}
+
+/// The sketch of `search_sig` matching `template` exactly, or else the
+/// first one that can be downsampled to it.
+pub fn prepare_query(
+    search_sig: &Signature,
+    template: &Sketch,
+) -> Result<KmerMinHash, PrepareQueryError> {
+    prepare_query_with_source(search_sig, template).map(|(mh, _)| mh)
+}
+
+/// Like `prepare_query`, but also return the sketch it was downsampled
+/// from, if it was.
+pub fn prepare_query_with_source(
+    search_sig: &Signature,
+    template: &Sketch,
+) -> Result<(KmerMinHash, Option<KmerMinHash>), PrepareQueryError> {
+    if let Some(Sketch::MinHash(mh)) = search_sig.select_sketch(template) {
+        return Ok((mh.clone(), None));
+    }
+    let template_mh = match template {
+        Sketch::MinHash(mh) => mh,
+        _ => return Err(PrepareQueryError::TemplateNotMinHash),
+    };
+
+    let mut reasons = vec![];
+    for sketch in search_sig.sketches() {
+        if let Sketch::MinHash(ref_mh) = sketch {
+            match check_compatible_downsample(&ref_mh, template_mh) {
+                Ok(()) => match downsample(&ref_mh, template_mh.scaled()) {
+                    Ok(mh) => return Ok((mh, Some(ref_mh))),
+                    Err(reason) => reasons.push(reason),
+                },
+                Err(reason) => reasons.push(reason),
+            }
+        }
+    }
+    Err(PrepareQueryError::NoCompatibleSketch(reasons))
+}
+
+fn downsample(mh: &KmerMinHash, scaled: u64) -> Result<KmerMinHash, CompatibilityError> {
+    mh.downsample_max_hash(max_hash_for_scaled(scaled))
+        .map_err(|e| CompatibilityError::Downsample {
+            scaled,
+            reason: e.to_string(),
+        })
+}
+
+/// Like `prepare_query`, but also accept a sketch with a coarser scaled
+/// than the template and return it without downsampling.
+pub fn prepare_query_any_scaled(
+    search_sig: &Signature,
+    template: &Sketch,
+) -> Result<KmerMinHash, PrepareQueryError> {
+    prepare_query_any_scaled_with_source(search_sig, template).map(|(mh, _)| mh)
+}
+
+/// Like `prepare_query_with_source`, accepting coarser sketches as
+/// `prepare_query_any_scaled` does.
+pub fn prepare_query_any_scaled_with_source(
+    search_sig: &Signature,
+    template: &Sketch,
+) -> Result<(KmerMinHash, Option<KmerMinHash>), PrepareQueryError> {
+    let reasons = match prepare_query_with_source(search_sig, template) {
+        Err(PrepareQueryError::NoCompatibleSketch(reasons)) => reasons,
+        prepared => return prepared,
+    };
+    let template_mh = match template {
+        Sketch::MinHash(mh) => mh,
+        _ => return Err(PrepareQueryError::TemplateNotMinHash),
+    };
+
+    for sketch in search_sig.sketches() {
+        if let Sketch::MinHash(ref_mh) = sketch {
+            if ref_mh.num() == 0
+                && ref_mh.ksize() == template_mh.ksize()
+                && ref_mh.hash_function() == template_mh.hash_function()
+                && ref_mh.seed() == template_mh.seed()
+            {
+                return Ok((ref_mh, None));
+            }
+        }
+    }
+    Err(PrepareQueryError::NoCompatibleSketch(reasons))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn minhash(ksize: u32, scaled: u64) -> KmerMinHash {
+        let mut mh = KmerMinHash::new(scaled, ksize, HashFunctions::murmur64_DNA, 42, false, 0);
+        for hash in [1, 10, 100, 1000, 10_000, u64::MAX / 2000, u64::MAX / 1500] {
+            mh.add_hash(hash);
+        }
+        mh
+    }
+
+    fn signature(sketches: Vec<KmerMinHash>) -> Signature {
+        let mut sig = Signature::default();
+        for mh in sketches {
+            sig.push(Sketch::MinHash(mh));
+        }
+        sig
+    }
+
+    fn template(ksize: u32, scaled: u64) -> Sketch {
+        Sketch::MinHash(KmerMinHash::new(
+            scaled,
+            ksize,
+            HashFunctions::murmur64_DNA,
+            42,
+            false,
+            0,
+        ))
+    }
+
+    #[test]
+    fn compatible() {
+        assert_eq!(
+            check_compatible_downsample(&minhash(31, 1000), &minhash(31, 1000)),
+            Ok(())
+        );
+        assert_eq!(
+            check_compatible_downsample(&minhash(31, 1000), &minhash(31, 2000)),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn num_mismatch() {
+        let num = KmerMinHash::new(0, 31, HashFunctions::murmur64_DNA, 42, false, 500);
+        assert_eq!(
+            check_compatible_downsample(&num, &minhash(31, 1000)),
+            Err(CompatibilityError::Num { me: 500, other: 0 })
+        );
+    }
+
+    #[test]
+    fn ksize_mismatch() {
+        assert_eq!(
+            check_compatible_downsample(&minhash(21, 1000), &minhash(31, 1000)),
+            Err(CompatibilityError::KSize { me: 21, other: 31 })
+        );
+    }
+
+    #[test]
+    fn hash_function_mismatch() {
+        let protein = KmerMinHash::new(1000, 30, HashFunctions::murmur64_protein, 42, false, 0);
+        assert_eq!(
+            check_compatible_downsample(&protein, &minhash(30, 1000)),
+            Err(CompatibilityError::HashFunction {
+                me: HashFunctions::murmur64_protein,
+                other: HashFunctions::murmur64_DNA,
+            })
+        );
+    }
+
+    #[test]
+    fn scaled_too_coarse() {
+        assert_eq!(
+            check_compatible_downsample(&minhash(31, 2000), &minhash(31, 1000)),
+            Err(CompatibilityError::Scaled {
+                me: 2000,
+                other: 1000
+            })
+        );
+    }
+
+    #[test]
+    fn seed_mismatch() {
+        let other_seed = KmerMinHash::new(1000, 31, HashFunctions::murmur64_DNA, 7, false, 0);
+        assert_eq!(
+            check_compatible_downsample(&other_seed, &minhash(31, 1000)),
+            Err(CompatibilityError::Seed { me: 7, other: 42 })
+        );
+    }
+
+    #[test]
+    fn downsample_error_display() {
+        let reason = CompatibilityError::Downsample {
+            scaled: 1000,
+            reason: "no space".into(),
+        };
+        assert_eq!(
+            reason.to_string(),
+            "could not downsample to scaled 1000: no space"
+        );
+    }
+
+    #[test]
+    fn exact_match() {
+        let sig = signature(vec![minhash(21, 1000), minhash(31, 1000)]);
+        let (mh, source) = prepare_query_with_source(&sig, &template(31, 1000)).unwrap();
+        assert_eq!(mh.ksize(), 31);
+        assert_eq!(mh.md5sum(), minhash(31, 1000).md5sum());
+        assert!(source.is_none());
+    }
+
+    #[test]
+    fn downsampled() {
+        let sig = signature(vec![minhash(31, 1000)]);
+        let (mh, source) = prepare_query_with_source(&sig, &template(31, 2000)).unwrap();
+        assert_eq!(mh.scaled(), 2000);
+        assert!(mh.size() < minhash(31, 1000).size());
+        assert!(mh.mins().iter().all(|hash| *hash <= mh.max_hash()));
+        assert_eq!(source.unwrap().md5sum(), minhash(31, 1000).md5sum());
+    }
+
+    #[test]
+    fn no_compatible_sketch() {
+        let sig = signature(vec![minhash(21, 1000), minhash(31, 5000)]);
+        assert_eq!(
+            prepare_query(&sig, &template(31, 1000)),
+            Err(PrepareQueryError::NoCompatibleSketch(vec![
+                CompatibilityError::KSize { me: 21, other: 31 },
+                CompatibilityError::Scaled {
+                    me: 5000,
+                    other: 1000
+                },
+            ]))
+        );
+        assert_eq!(
+            prepare_query(&signature(vec![]), &template(31, 1000)),
+            Err(PrepareQueryError::NoCompatibleSketch(vec![]))
+        );
+    }
+
+    #[test]
+    fn any_scaled_accepts_coarser() {
+        let sig = signature(vec![minhash(21, 1000), minhash(31, 5000)]);
+        let (mh, source) = prepare_query_any_scaled_with_source(&sig, &template(31, 1000)).unwrap();
+        assert_eq!(mh.scaled(), 5000);
+        assert!(source.is_none());
+        assert!(prepare_query_any_scaled(&sig, &template(25, 1000)).is_err());
+    }
+}
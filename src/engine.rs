@@ -0,0 +1,171 @@
+//! Gather engines operating on an already-loaded set of candidate matches.
+
+use std::collections::{BinaryHeap, HashMap, HashSet};
+
+use rayon::prelude::*;
+
+use sourmash::signature::SigsTrait;
+use sourmash::sketch::minhash::KmerMinHash;
+
+use crate::candidate::{prefetch, Candidate};
+use crate::result::GatherResult;
+
+/// Convert a bp threshold into a minimum number of shared hashes.
+pub fn threshold_hashes_for(threshold_bp: u64, scaled: u64) -> u64 {
+    u64::max(1, threshold_bp / u64::max(1, scaled))
+}
+
+/// What the gather engines report while they run, so that front-ends can
+/// show progress as it happens.
+pub enum GatherEvent<'a> {
+    /// `loaded` of the `total` matchlist files were loaded and compared
+    /// with the query; only sent by gathers that load a matchlist, not by
+    /// the engines here, whose database is already loaded.
+    LoadProgress { loaded: usize, total: usize },
+    /// A database entry reaches the threshold and is a candidate match.
+    CandidateFound(&'a Candidate),
+    /// A round picked `result`, leaving `remaining_hashes` query hashes and
+    /// `candidates` candidate matches.
+    RoundComplete {
+        result: &'a GatherResult,
+        remaining_hashes: usize,
+        candidates: usize,
+    },
+}
+
+/// Run the gather loop for one query against a loaded database, rescanning
+/// all remaining candidates after each removal.
+///
+/// The database is already loaded, so `on_event` is called with every
+/// `CandidateFound` and `RoundComplete` event as soon as it happens.
+pub fn heap_gather<F>(
+    mut query: KmerMinHash,
+    db: &[Candidate],
+    threshold_bp: u64,
+    mut on_event: F,
+) -> Result<Vec<GatherResult>, sourmash::Error>
+where
+    F: FnMut(GatherEvent),
+{
+    let threshold_hashes = threshold_hashes_for(threshold_bp, query.scaled());
+    let orig_query = query.clone();
+    let query_md5 = orig_query.md5sum();
+
+    let candidates: Vec<Candidate> = db
+        .par_iter()
+        .filter_map(|entry| {
+            let containment = entry.minhash.count_common(&query, false).ok()?;
+            (containment >= threshold_hashes).then(|| Candidate {
+                containment,
+                ..entry.clone()
+            })
+        })
+        .collect();
+    for candidate in &candidates {
+        on_event(GatherEvent::CandidateFound(candidate));
+    }
+    let mut matching_sketches = BinaryHeap::from(candidates);
+
+    let mut results = vec![];
+    while let Some(best_element) = matching_sketches.peek() {
+        results.push(GatherResult::new(
+            results.len(),
+            best_element,
+            best_element.containment,
+            query.size(),
+            &orig_query,
+            &query_md5,
+        )?);
+        query.remove_from(&best_element.minhash)?;
+        matching_sketches = prefetch(&query, matching_sketches, threshold_hashes);
+        on_event(GatherEvent::RoundComplete {
+            result: results.last().unwrap(),
+            remaining_hashes: query.size(),
+            candidates: matching_sketches.len(),
+        });
+    }
+    Ok(results)
+}
+
+/// Run the gather loop using an inverted index from query hashes to the
+/// candidates containing them.
+///
+/// Instead of rescanning every candidate after each round, only the
+/// counters of candidates sharing a removed hash are decremented.
+///
+/// `on_event` gets the same `CandidateFound` and `RoundComplete` events as
+/// from `heap_gather`.
+pub fn counter_gather<F>(
+    query: &KmerMinHash,
+    db: &[Candidate],
+    threshold_bp: u64,
+    mut on_event: F,
+) -> Result<Vec<GatherResult>, sourmash::Error>
+where
+    F: FnMut(GatherEvent),
+{
+    let threshold_hashes = threshold_hashes_for(threshold_bp, query.scaled());
+    let mut remaining: HashSet<u64> = query.iter_mins().cloned().collect();
+
+    // hash -> candidates containing it, and per-candidate overlap counts.
+    let mut index: HashMap<u64, Vec<usize>> = HashMap::new();
+    let mut counts: Vec<u64> = vec![0; db.len()];
+    for (i, entry) in db.iter().enumerate() {
+        for hash in entry.minhash.iter_mins() {
+            if remaining.contains(hash) {
+                index.entry(*hash).or_default().push(i);
+                counts[i] += 1;
+            }
+        }
+    }
+    let is_candidate = |count: &u64| *count > 0 && *count >= threshold_hashes;
+    for (entry, &containment) in db.iter().zip(&counts) {
+        if is_candidate(&containment) {
+            on_event(GatherEvent::CandidateFound(&Candidate {
+                containment,
+                ..entry.clone()
+            }));
+        }
+    }
+
+    let query_md5 = query.md5sum();
+    let mut results = vec![];
+    loop {
+        // largest overlap wins; ties as in heap_gather.
+        let best = counts
+            .iter()
+            .enumerate()
+            .max_by(|(i, a), (j, b)| a.cmp(b).then_with(|| db[*i].break_tie(&db[*j])));
+        let (best, &count) = match best {
+            Some(best) => best,
+            None => break,
+        };
+        if !is_candidate(&count) {
+            break;
+        }
+
+        let entry = &db[best];
+        results.push(GatherResult::new(
+            results.len(),
+            entry,
+            count,
+            remaining.len(),
+            query,
+            &query_md5,
+        )?);
+
+        for hash in entry.minhash.iter_mins() {
+            if remaining.remove(hash) {
+                for &i in &index[hash] {
+                    counts[i] -= 1;
+                }
+            }
+        }
+        on_event(GatherEvent::RoundComplete {
+            result: results.last().unwrap(),
+            remaining_hashes: remaining.len(),
+            candidates: counts.iter().filter(|count| is_candidate(count)).count(),
+        });
+    }
+    Ok(results)
+}
@@ -0,0 +1,134 @@
+//! `--explain-match`: gather over the candidates again, following one
+//! match, to see which of its query hashes the matches ranked above it
+//! took, and so why its unique overlap is as small as it is.
+//!
+//! The hashes it lost are written as a signature to `<prefix>.lost.sig`,
+//! and how many went to each earlier match to `<prefix>.csv`.
+
+use std::collections::BinaryHeap;
+use std::path::Path;
+
+use log::info;
+use serde::Serialize;
+
+use sourmash::signature::SigsTrait;
+use sourmash::sketch::minhash::KmerMinHash;
+
+use crate::{claimed_by, index, output, write_minhash_sig, Candidate, GatherArgs, RankBy};
+
+#[derive(Serialize)]
+struct LostRow {
+    gather_rank: usize,
+    name: String,
+    match_md5: String,
+    lost_hashes: usize,
+    lost_bp: u64,
+    /// fraction of the explained match's overlap with the original query
+    f_lost: f64,
+}
+
+fn selects(selector: &str, candidate: &Candidate) -> bool {
+    candidate.name == selector
+        || crate::ident_for_name(&candidate.name) == selector
+        || candidate.md5.starts_with(selector)
+}
+
+/// Gather `orig_query` over `candidates` for at most `n_rounds` rounds, as
+/// the run did, until the match named or with md5 `selector` is picked.
+pub(crate) fn explain_match(
+    orig_query: &KmerMinHash,
+    candidates: BinaryHeap<Candidate>,
+    selector: &str,
+    args: &GatherArgs,
+    threshold_hashes: u64,
+    rank_by: RankBy,
+    n_rounds: usize,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let target = candidates
+        .iter()
+        .find(|candidate| selects(selector, candidate))
+        .cloned()
+        .ok_or_else(|| {
+            format!(
+                "--explain-match: no candidate named or with md5 {} reaches the threshold",
+                selector
+            )
+        })?;
+    let prefix = args.explain_prefix.as_ref().unwrap();
+    let scaled = orig_query.scaled();
+
+    let mut query = orig_query.clone();
+    let orig_overlap = claimed_by(orig_query, &target.minhash)?;
+    let mut kept = orig_overlap.clone();
+    let mut rows = vec![];
+    let mut target_rank = None;
+
+    let mut matching_sketches: Box<dyn index::Index> =
+        Box::new(index::HeapIndex::new(candidates, args.speculate));
+    let mut rank = 0;
+    while rank < n_rounds {
+        let best = match matching_sketches.best_match(&query, threshold_hashes, rank_by)? {
+            Some(best) => best,
+            None => break,
+        };
+        // left out of the run, as in gather.
+        if args.below_min_unique(best.containment * scaled) {
+            continue;
+        }
+        if best.md5 == target.md5 {
+            target_rank = Some(rank);
+            break;
+        }
+        let lost = claimed_by(&kept, &best.minhash)?;
+        if lost.size() > 0 {
+            rows.push(LostRow {
+                gather_rank: rank,
+                name: args.format_name(&best.name),
+                match_md5: best.md5.clone(),
+                lost_hashes: lost.size(),
+                lost_bp: lost.size() as u64 * scaled,
+                f_lost: lost.size() as f64 / orig_overlap.size() as f64,
+            });
+            kept.remove_from(&lost)?;
+        }
+        if let Some(min_fraction) = args.prune_contained {
+            let claimed = claimed_by(&query, &best.minhash)?;
+            matching_sketches.prune_contained(&claimed, min_fraction);
+        }
+        query.remove_from(&best.minhash)?;
+        matching_sketches.remove_hashes(&query, threshold_hashes);
+        rank += 1;
+    }
+
+    let mut lost = orig_overlap.clone();
+    lost.remove_from(&kept)?;
+    let sig_path = args.signature_path(Path::new(&format!("{}.lost.sig", prefix.display())));
+    write_minhash_sig(&sig_path, &format!("{} lost", target.name), &lost)?;
+
+    let csv_path = format!("{}.csv", prefix.display());
+    let mut writer = output::create_csv(&csv_path)?;
+    for row in &rows {
+        writer.serialize(row)?;
+    }
+    output::finish_csv(writer)?;
+
+    let picked = match target_rank {
+        Some(rank) => format!("picked at rank {}", rank),
+        None => "not picked".into(),
+    };
+    info!(
+        "{}: {} of its {} query hashes lost to {} matches ranked above it, {} left; {}",
+        target.name,
+        lost.size(),
+        orig_overlap.size(),
+        rows.len(),
+        kept.size(),
+        picked
+    );
+    info!(
+        "Wrote the lost hashes to {} and who took them to {}",
+        sig_path.display(),
+        csv_path
+    );
+    Ok(())
+}
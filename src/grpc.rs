@@ -0,0 +1,161 @@
+//! gRPC front-end streaming gather rounds as they are computed; enabled
+//! with the `grpc` feature.
+
+use std::sync::Arc;
+
+use log::info;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{transport::Server, Request, Response, Status};
+
+use sourmash::signature::{Signature, SigsTrait};
+use sourmash::sketch::Sketch;
+
+use crate::engine::{self, heap_gather};
+use crate::serve::{db_paths, load_db, ServeArgs};
+use crate::{ksize_of, select_query, Candidate};
+
+pub mod proto {
+    tonic::include_proto!("countergather");
+}
+
+use proto::gather_event::Event;
+use proto::gather_server::{Gather, GatherServer};
+use proto::{Done, GatherEvent, GatherRequest, GatherRound, InfoReply, InfoRequest, Progress};
+
+struct GatherService {
+    template: Arc<Sketch>,
+    db: Arc<Vec<Candidate>>,
+    info: InfoReply,
+    threshold_bp: u64,
+}
+
+fn progress(message: String, remaining_hashes: usize, candidates: usize) -> GatherEvent {
+    GatherEvent {
+        event: Some(Event::Progress(Progress {
+            message,
+            remaining_hashes: remaining_hashes as u64,
+            candidates: candidates as u64,
+        })),
+    }
+}
+
+#[tonic::async_trait]
+impl Gather for GatherService {
+    async fn info(&self, _request: Request<InfoRequest>) -> Result<Response<InfoReply>, Status> {
+        Ok(Response::new(self.info.clone()))
+    }
+
+    type GatherStream = ReceiverStream<Result<GatherEvent, Status>>;
+
+    async fn gather(
+        &self,
+        request: Request<GatherRequest>,
+    ) -> Result<Response<Self::GatherStream>, Status> {
+        let request = request.into_inner();
+        let sigs = Signature::from_reader(&request.signature[..])
+            .map_err(|e| Status::invalid_argument(format!("could not parse signature: {}", e)))?;
+        let (query_name, query, _) = select_query(&sigs, &self.template)
+            .map_err(|e| Status::failed_precondition(e.to_string()))?;
+        let threshold_bp = if request.threshold_bp > 0 {
+            request.threshold_bp
+        } else {
+            self.threshold_bp
+        };
+
+        let (tx, rx) = mpsc::channel(16);
+        let db = self.db.clone();
+        tokio::task::spawn_blocking(move || {
+            let _ = tx.blocking_send(Ok(progress(
+                format!("gathering {}", query_name),
+                query.size(),
+                db.len(),
+            )));
+
+            let results = heap_gather(query, &db, threshold_bp, |event| {
+                let (result, remaining, candidates) = match event {
+                    engine::GatherEvent::RoundComplete {
+                        result,
+                        remaining_hashes,
+                        candidates,
+                    } => (result, remaining_hashes, candidates),
+                    // the database is already loaded, so there is no load
+                    // progress.
+                    _ => return,
+                };
+                let round = GatherEvent {
+                    event: Some(Event::Round(GatherRound {
+                        gather_rank: result.gather_rank as u64,
+                        name: result.name.clone(),
+                        md5: result.md5.clone(),
+                        query_md5: result.query_md5.clone(),
+                        unique_intersect_hashes: result.unique_intersect_hashes,
+                        f_unique_to_query: result.f_unique_to_query,
+                        intersect_bp: result.intersect_bp,
+                        unique_intersect_bp: result.unique_intersect_bp,
+                        f_orig_query: result.f_orig_query,
+                        f_match: result.f_match,
+                        jaccard: result.jaccard,
+                        remaining_bp: result.remaining_bp,
+                        f_found_cumulative: result.f_found_cumulative,
+                    })),
+                };
+                let _ = tx.blocking_send(Ok(round));
+                let _ = tx.blocking_send(Ok(progress(
+                    format!("round {} done", result.gather_rank),
+                    remaining,
+                    candidates,
+                )));
+            });
+
+            let event = match results {
+                Ok(results) => {
+                    info!("{}: {} matches", query_name, results.len());
+                    Ok(GatherEvent {
+                        event: Some(Event::Done(Done {
+                            n_matches: results.len() as u64,
+                        })),
+                    })
+                }
+                Err(e) => Err(Status::internal(e.to_string())),
+            };
+            let _ = tx.blocking_send(event);
+        });
+
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+}
+
+pub fn serve_grpc(args: &ServeArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let template_mh = args.template()?;
+    let template = Sketch::MinHash(template_mh.clone());
+
+    info!("Loading database from {}", args.db.display());
+    let paths = db_paths(&args.db)?;
+    let db = load_db(&paths, &template);
+    info!("Loaded {} sketches from {} files", db.len(), paths.len());
+
+    let service = GatherService {
+        info: InfoReply {
+            sketches: db.len() as u64,
+            ksize: ksize_of(&template_mh) as u64,
+            scaled: template_mh.scaled(),
+            moltype: template_mh.hash_function().to_string(),
+            seed: template_mh.seed(),
+        },
+        template: Arc::new(template),
+        db: Arc::new(db),
+        threshold_bp: args.threshold_bp,
+    };
+
+    let address = format!("{}:{}", args.host, args.port).parse()?;
+    info!("Listening for gRPC on {}", address);
+
+    let runtime = tokio::runtime::Runtime::new()?;
+    runtime.block_on(
+        Server::builder()
+            .add_service(GatherServer::new(service))
+            .serve(address),
+    )?;
+    Ok(())
+}
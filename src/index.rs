@@ -0,0 +1,260 @@
+//! The candidate matches the gather loop picks from, behind the `Index`
+//! trait, so that other backends can plug in without changing the loop.
+
+use std::collections::BinaryHeap;
+
+use rayon::prelude::*;
+use sourmash::sketch::minhash::KmerMinHash;
+
+use crate::candidate::{prefetch, weighted_overlap, Candidate};
+use crate::split;
+
+pub trait Index {
+    /// How many candidates are left.
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The remaining candidates, e.g. for `--output-prefetch`. Their
+    /// sketches are what the index keeps: trimmed to the query, or empty
+    /// if it keeps none.
+    fn prefetch(&self) -> Vec<Candidate>;
+
+    /// Remove and return the best remaining match for the query.
+    fn best_match(
+        &mut self,
+        query: &KmerMinHash,
+        threshold_hashes: u64,
+        rank_by: RankBy,
+    ) -> Result<Option<Candidate>, Box<dyn std::error::Error>>;
+
+    /// Catch up with the hashes of the last match being removed from the
+    /// query, dropping candidates that fall below the threshold.
+    fn remove_hashes(&mut self, query: &KmerMinHash, threshold_hashes: u64);
+
+    /// Drop the candidates sharing at least `min_fraction` of their
+    /// overlap with the query with `claimed`, the hashes the selected match
+    /// is about to remove; returns how many were dropped.
+    fn prune_contained(&mut self, _claimed: &KmerMinHash, _min_fraction: f64) -> usize {
+        0
+    }
+
+    /// Remove and return the candidates whose overlap can't be told apart
+    /// from that of `best`, just picked, for `--fair-split`.
+    fn pop_coequal(&mut self, _best: &Candidate, _z: f64) -> Vec<Candidate> {
+        vec![]
+    }
+
+    /// Put back candidates `pop_coequal` returned, scored against the
+    /// current query.
+    fn restore(&mut self, _candidates: Vec<Candidate>) {}
+
+    /// Stop any loading still going on and return the matchlist files
+    /// that failed to load after gather began, e.g. on a reload.
+    fn stop_loading(&mut self) -> Vec<std::path::PathBuf> {
+        vec![]
+    }
+
+    /// Called once gather is done, e.g. to log statistics.
+    fn finish(&self) {}
+}
+
+/// Every candidate kept in memory and rescored after each round.
+///
+/// With `speculate`, only the top K candidates are rescored after a
+/// removal, in parallel. Their old overlaps are upper bounds, so the best
+/// rescored one is the best match if it still beats the K+1th candidate's
+/// old overlap; otherwise everything is rescored.
+pub struct HeapIndex {
+    heap: BinaryHeap<Candidate>,
+    speculate: Option<usize>,
+    /// overlaps in the heap are from before the last removal
+    stale: bool,
+    n_fallbacks: usize,
+}
+
+impl HeapIndex {
+    pub fn new(heap: BinaryHeap<Candidate>, speculate: Option<usize>) -> HeapIndex {
+        HeapIndex {
+            heap,
+            speculate,
+            stale: false,
+            n_fallbacks: 0,
+        }
+    }
+
+    /// Add candidates scored against an earlier query, rescoring them
+    /// against `query`.
+    pub fn extend(
+        &mut self,
+        candidates: Vec<Candidate>,
+        query: &KmerMinHash,
+        threshold_hashes: u64,
+    ) {
+        self.heap
+            .extend(prefetch(query, candidates.into(), threshold_hashes));
+    }
+
+    /// Put back a candidate scored against the current query.
+    pub fn push(&mut self, candidate: Candidate) {
+        self.heap.push(candidate);
+    }
+
+    fn refresh(&mut self, query: &KmerMinHash, threshold_hashes: u64) {
+        self.heap = prefetch(query, std::mem::take(&mut self.heap), threshold_hashes);
+        self.stale = false;
+    }
+
+    /// Rescore the top `k` candidates and return the best one if it is
+    /// provably the best match.
+    fn pop_speculative(
+        &mut self,
+        query: &KmerMinHash,
+        threshold_hashes: u64,
+        k: usize,
+    ) -> Option<Candidate> {
+        let top: BinaryHeap<Candidate> = (0..k).map_while(|_| self.heap.pop()).collect();
+        let mut top = prefetch(query, top, threshold_hashes);
+        // the next candidate's old overlap is an upper bound, so a rescored
+        // candidate ordered before it is ordered before everything left.
+        let best_so_far = match (top.peek(), self.heap.peek()) {
+            (Some(best), Some(next)) => best >= next,
+            (Some(_), None) => true,
+            (None, _) => false,
+        };
+        match top.peek() {
+            Some(_) if best_so_far => {
+                let best = top.pop();
+                self.heap.extend(top);
+                best
+            }
+            _ if self.heap.is_empty() => None,
+            _ => {
+                self.heap.extend(top);
+                self.n_fallbacks += 1;
+                self.refresh(query, threshold_hashes);
+                self.heap.pop()
+            }
+        }
+    }
+}
+
+impl Index for HeapIndex {
+    fn len(&self) -> usize {
+        self.heap.len()
+    }
+
+    fn prefetch(&self) -> Vec<Candidate> {
+        self.heap.iter().cloned().collect()
+    }
+
+    fn best_match(
+        &mut self,
+        query: &KmerMinHash,
+        threshold_hashes: u64,
+        rank_by: RankBy,
+    ) -> Result<Option<Candidate>, Box<dyn std::error::Error>> {
+        if let (true, Some(k), RankBy::Overlap) = (self.stale, self.speculate, rank_by) {
+            return Ok(self.pop_speculative(query, threshold_hashes, k));
+        }
+        if self.stale {
+            self.refresh(query, threshold_hashes);
+        }
+        if rank_by == RankBy::Overlap {
+            return Ok(self.heap.pop());
+        }
+        let mut candidates = std::mem::take(&mut self.heap).into_vec();
+        let best = candidates
+            .iter()
+            .map(|c| rank_by.score(c, query))
+            .enumerate()
+            .max_by(|(i, a), (j, b)| {
+                a.total_cmp(b)
+                    .then_with(|| candidates[*i].cmp(&candidates[*j]))
+                    .then_with(|| candidates[*j].name.cmp(&candidates[*i].name))
+            })
+            .map(|(i, _)| i);
+        let best = best.map(|i| candidates.swap_remove(i));
+        self.heap = candidates.into();
+        Ok(best)
+    }
+
+    /// Overlaps that are stale after speculation are upper bounds, so
+    /// they only make pruning more conservative.
+    fn prune_contained(&mut self, claimed: &KmerMinHash, min_fraction: f64) -> usize {
+        let n_before = self.heap.len();
+        self.heap = std::mem::take(&mut self.heap)
+            .into_par_iter()
+            .filter(|candidate| {
+                let shared = candidate.minhash.count_common(claimed, false).unwrap_or(0);
+                (shared as f64) < min_fraction * candidate.containment as f64
+            })
+            .collect();
+        n_before - self.heap.len()
+    }
+
+    /// Overlaps are fresh after `best_match` without speculation, which
+    /// `--fair-split` conflicts with, so the heap yields them in order.
+    fn pop_coequal(&mut self, best: &Candidate, z: f64) -> Vec<Candidate> {
+        let mut coequal = vec![];
+        while let Some(next) = self.heap.peek() {
+            if !split::coequal(best.containment, next.containment, z) {
+                break;
+            }
+            coequal.extend(self.heap.pop());
+        }
+        coequal
+    }
+
+    fn restore(&mut self, candidates: Vec<Candidate>) {
+        self.heap.extend(candidates);
+    }
+
+    fn remove_hashes(&mut self, query: &KmerMinHash, threshold_hashes: u64) {
+        if self.speculate.is_some() {
+            self.stale = true;
+        } else {
+            self.refresh(query, threshold_hashes);
+        }
+    }
+
+    fn finish(&self) {
+        if self.speculate.is_some() {
+            log::debug!(
+                "speculation fell back to a full rescore {} times",
+                self.n_fallbacks
+            );
+        }
+    }
+}
+
+/// The greedy criterion used to pick the next match.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RankBy {
+    /// most hashes shared with the remaining query
+    Overlap,
+    /// largest fraction of the match found in the remaining query
+    Containment,
+    /// most shared hashes, weighted by their query abundance
+    Weighted,
+}
+
+impl RankBy {
+    pub fn from_name(name: &str) -> RankBy {
+        match name {
+            "containment" => RankBy::Containment,
+            "weighted" => RankBy::Weighted,
+            _ => RankBy::Overlap,
+        }
+    }
+
+    pub fn score(&self, candidate: &Candidate, query: &KmerMinHash) -> f64 {
+        match self {
+            RankBy::Overlap => candidate.containment as f64,
+            RankBy::Containment => candidate.containment as f64 / candidate.match_size as f64,
+            RankBy::Weighted => weighted_overlap(query, &candidate.minhash) as f64,
+        }
+    }
+}
@@ -0,0 +1,284 @@
+//! Gather candidates that are reloaded from disk on demand, instead of
+//! keeping every matching sketch in memory for the whole run.
+
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, VecDeque};
+use std::path::{Path, PathBuf};
+
+use log::warn;
+use sourmash::sketch::minhash::KmerMinHash;
+use sourmash::sketch::Sketch;
+
+use crate::index::{Index, RankBy};
+use crate::loader::{self, RetryPolicy};
+use crate::{prepare_query, Candidate};
+
+/// What we remember about a candidate between rounds. `containment` is
+/// an upper bound: the query only shrinks, so overlaps only go down.
+pub(crate) struct LazyCandidate {
+    path: PathBuf,
+    name: String,
+    filename: String,
+    db: Option<String>,
+    group: Option<String>,
+    md5: String,
+    source_md5: Option<String>,
+    match_size: usize,
+    containment: u64,
+}
+
+impl LazyCandidate {
+    /// Keep the summary of a loaded candidate, dropping its sketch.
+    pub(crate) fn new(path: PathBuf, result: Candidate) -> LazyCandidate {
+        LazyCandidate {
+            path,
+            name: result.name,
+            filename: result.filename,
+            db: result.db,
+            group: result.group,
+            md5: result.md5,
+            source_md5: result.source_md5,
+            match_size: result.match_size,
+            containment: result.containment,
+        }
+    }
+
+    /// The candidate with an empty sketch of the template's parameters.
+    fn to_candidate(&self, template: &Sketch) -> Candidate {
+        let mut minhash = match template {
+            Sketch::MinHash(mh) => mh.clone(),
+            _ => KmerMinHash::default(),
+        };
+        minhash.clear();
+        Candidate {
+            name: self.name.clone(),
+            filename: self.filename.clone(),
+            db: self.db.clone(),
+            group: self.group.clone(),
+            minhash,
+            containment: self.containment,
+            md5: self.md5.clone(),
+            source_md5: self.source_md5.clone(),
+            match_size: self.match_size,
+        }
+    }
+}
+
+/// Ordered like `Candidate`, so that ties are picked the same way.
+impl Ord for LazyCandidate {
+    fn cmp(&self, other: &LazyCandidate) -> Ordering {
+        self.containment
+            .cmp(&other.containment)
+            .then_with(|| other.name.cmp(&self.name))
+            .then_with(|| other.md5.cmp(&self.md5))
+    }
+}
+
+impl PartialOrd for LazyCandidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for LazyCandidate {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for LazyCandidate {}
+
+/// The most recently reloaded sketches, keyed by md5.
+struct SketchCache {
+    capacity: usize,
+    entries: VecDeque<(String, KmerMinHash)>,
+}
+
+impl SketchCache {
+    fn get(&mut self, md5: &str) -> Option<KmerMinHash> {
+        let pos = self.entries.iter().position(|(k, _)| k == md5)?;
+        let entry = self.entries.remove(pos).unwrap();
+        let mh = entry.1.clone();
+        self.entries.push_back(entry);
+        Some(mh)
+    }
+
+    fn insert(&mut self, md5: String, mh: KmerMinHash) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back((md5, mh));
+    }
+}
+
+/// Candidates picked by lazy greedy selection: the top candidate is
+/// reloaded and rescored, and is the best match once its current overlap
+/// is no smaller than the next candidate's (stale, upper bound) overlap.
+pub(crate) struct LazyMatches {
+    heap: BinaryHeap<LazyCandidate>,
+    template: Sketch,
+    cache: SketchCache,
+    cache_dir: Option<PathBuf>,
+    retry: RetryPolicy,
+    n_reloads: usize,
+    /// files that could not be reloaded; their candidates are dropped
+    failed: Vec<PathBuf>,
+}
+
+impl LazyMatches {
+    pub(crate) fn new(
+        candidates: Vec<LazyCandidate>,
+        template: &Sketch,
+        cache_size: usize,
+        cache_dir: Option<&Path>,
+        retry: RetryPolicy,
+    ) -> LazyMatches {
+        LazyMatches {
+            heap: candidates.into(),
+            template: template.clone(),
+            cache: SketchCache {
+                capacity: cache_size,
+                entries: VecDeque::new(),
+            },
+            cache_dir: cache_dir.map(Path::to_path_buf),
+            retry,
+            n_reloads: 0,
+            failed: vec![],
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.heap.len()
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &LazyCandidate> {
+        self.heap.iter()
+    }
+
+    /// How many sketches were read back from disk so far.
+    pub(crate) fn n_reloads(&self) -> usize {
+        self.n_reloads
+    }
+
+    /// Forget the files that could not be reloaded, returning them.
+    pub(crate) fn take_failed(&mut self) -> Vec<PathBuf> {
+        std::mem::take(&mut self.failed)
+    }
+
+    /// Get a candidate's sketch, from the cache or from its file.
+    fn reload(&mut self, candidate: &LazyCandidate) -> Result<KmerMinHash, String> {
+        if let Some(mh) = self.cache.get(&candidate.md5) {
+            return Ok(mh);
+        }
+
+        self.n_reloads += 1;
+        let sigs =
+            loader::load_with_retry(&candidate.path, self.cache_dir.as_deref(), &self.retry)?;
+        let mut reasons = vec![];
+        let mut found = None;
+        for sig in &sigs {
+            match prepare_query(sig, &self.template) {
+                Ok(mh) if mh.md5sum() == candidate.md5 => {
+                    found = Some(mh);
+                    break;
+                }
+                Ok(_) => {}
+                Err(e) => reasons.push(e.to_string()),
+            }
+        }
+        let mh = found.ok_or_else(|| {
+            let mut message = format!(
+                "{}: sketch {} changed on disk since it was loaded",
+                candidate.path.display(),
+                candidate.md5
+            );
+            if !reasons.is_empty() {
+                message = format!("{} ({})", message, reasons.join("; "));
+            }
+            message
+        })?;
+        self.cache.insert(candidate.md5.clone(), mh.clone());
+        Ok(mh)
+    }
+
+    /// Remove and return the best remaining match for the query, reloading
+    /// its full sketch. Candidates whose file can't be reloaded are logged,
+    /// recorded and dropped.
+    pub(crate) fn pop_best(
+        &mut self,
+        query: &KmerMinHash,
+        threshold_hashes: u64,
+    ) -> Result<Option<Candidate>, Box<dyn std::error::Error>> {
+        while let Some(mut candidate) = self.heap.pop() {
+            let mh = match self.reload(&candidate) {
+                Ok(mh) => mh,
+                Err(e) => {
+                    warn!("could not reload {}: {}", candidate.path.display(), e);
+                    if !self.failed.contains(&candidate.path) {
+                        self.failed.push(candidate.path);
+                    }
+                    continue;
+                }
+            };
+            let containment = mh.count_common(query, false)?;
+            if containment < threshold_hashes {
+                continue;
+            }
+
+            candidate.containment = containment;
+            let still_best = self.heap.peek().is_none_or(|next| candidate >= *next);
+            if still_best {
+                return Ok(Some(Candidate {
+                    name: candidate.name,
+                    filename: candidate.filename,
+                    db: candidate.db,
+                    group: candidate.group,
+                    minhash: mh,
+                    containment,
+                    md5: candidate.md5,
+                    source_md5: candidate.source_md5,
+                    match_size: candidate.match_size,
+                }));
+            }
+            self.heap.push(candidate);
+        }
+        Ok(None)
+    }
+}
+
+impl Index for LazyMatches {
+    fn len(&self) -> usize {
+        LazyMatches::len(self)
+    }
+
+    /// The candidates without their sketches, which aren't kept.
+    fn prefetch(&self) -> Vec<Candidate> {
+        self.iter()
+            .map(|c| c.to_candidate(&self.template))
+            .collect()
+    }
+
+    /// Only `RankBy::Overlap` is supported.
+    fn best_match(
+        &mut self,
+        query: &KmerMinHash,
+        threshold_hashes: u64,
+        _rank_by: RankBy,
+    ) -> Result<Option<Candidate>, Box<dyn std::error::Error>> {
+        self.pop_best(query, threshold_hashes)
+    }
+
+    /// Overlaps are recomputed when candidates are reloaded.
+    fn remove_hashes(&mut self, _query: &KmerMinHash, _threshold_hashes: u64) {}
+
+    fn stop_loading(&mut self) -> Vec<std::path::PathBuf> {
+        self.take_failed()
+    }
+
+    fn finish(&self) {
+        log::debug!("reloaded {} sketches from disk", self.n_reloads());
+    }
+}
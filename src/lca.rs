@@ -0,0 +1,136 @@
+//! Reading sourmash LCA databases (`.lca.json`, optionally gzipped).
+//!
+//! An LCA database maps each hash to the signatures that contain it; we
+//! invert that back into one sketch per signature so it can be used as a
+//! matchlist, and keep the lineages for taxonomy summaries.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::Path;
+
+use flate2::read::GzDecoder;
+use log::info;
+use serde::Deserialize;
+
+use sourmash::encodings::HashFunctions;
+use sourmash::signature::Signature;
+use sourmash::sketch::Sketch;
+
+use crate::compute::empty_minhash;
+
+/// sourmash doesn't record the seed in LCA databases; they all use this.
+const LCA_SEED: u64 = 42;
+
+#[derive(Deserialize)]
+struct LcaJson {
+    #[serde(rename = "type")]
+    db_type: String,
+    ksize: u32,
+    scaled: u64,
+    moltype: String,
+    lid_to_lineage: HashMap<String, Vec<(String, String)>>,
+    hashval_assignments: HashMap<String, Vec<u32>>,
+    ident_to_name: HashMap<String, String>,
+    ident_to_idx: HashMap<String, u32>,
+    idx_to_lid: HashMap<String, u32>,
+}
+
+pub struct LcaDatabase {
+    json: LcaJson,
+}
+
+pub fn is_lca_db<P: AsRef<Path>>(path: P) -> bool {
+    path.as_ref()
+        .file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| name.ends_with(".lca.json") || name.ends_with(".lca.json.gz"))
+}
+
+impl LcaDatabase {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<LcaDatabase, Box<dyn std::error::Error>> {
+        let path = path.as_ref();
+        let file = BufReader::new(File::open(path)?);
+        let reader: Box<dyn Read> = if path.extension().is_some_and(|ext| ext == "gz") {
+            Box::new(GzDecoder::new(file))
+        } else {
+            Box::new(file)
+        };
+        let json: LcaJson = serde_json::from_reader(reader)?;
+        if json.db_type != "sourmash_lca" {
+            return Err(format!("{} is not a sourmash LCA database", path.display()).into());
+        }
+        info!(
+            "Loaded LCA database {}: k={} scaled={} moltype={}, {} signatures",
+            path.display(),
+            json.ksize,
+            json.scaled,
+            json.moltype,
+            json.ident_to_idx.len()
+        );
+        Ok(LcaDatabase { json })
+    }
+
+    fn hash_function(&self) -> Result<HashFunctions, Box<dyn std::error::Error>> {
+        match self.json.moltype.to_lowercase().as_str() {
+            "dna" => Ok(HashFunctions::murmur64_DNA),
+            "protein" => Ok(HashFunctions::murmur64_protein),
+            "dayhoff" => Ok(HashFunctions::murmur64_dayhoff),
+            "hp" => Ok(HashFunctions::murmur64_hp),
+            other => Err(format!("unknown LCA database moltype {}", other).into()),
+        }
+    }
+
+    /// Rebuild one signature per identifier from the hash assignments.
+    pub fn signatures(&self) -> Result<Vec<Signature>, Box<dyn std::error::Error>> {
+        let hash_function = self.hash_function()?;
+        let mut hashes: HashMap<u32, Vec<u64>> = HashMap::new();
+        for (hashval, idxs) in &self.json.hashval_assignments {
+            let hashval: u64 = hashval.parse()?;
+            for idx in idxs {
+                hashes.entry(*idx).or_default().push(hashval);
+            }
+        }
+
+        let mut idents: Vec<(&String, &u32)> = self.json.ident_to_idx.iter().collect();
+        idents.sort_by_key(|(_, idx)| **idx);
+
+        let mut sigs = vec![];
+        for (ident, idx) in idents {
+            let mut mh = empty_minhash(
+                self.json.ksize,
+                self.json.scaled,
+                hash_function,
+                LCA_SEED,
+                false,
+            );
+            if let Some(hashes) = hashes.get_mut(idx) {
+                hashes.sort_unstable();
+                mh.add_many(hashes)?;
+            }
+            let name = self.json.ident_to_name.get(ident).unwrap_or(ident);
+            sigs.push(
+                Signature::builder()
+                    .hash_function("0.murmur64")
+                    .name(Some(name.clone()))
+                    .filename(Some(ident.clone()))
+                    .signatures(vec![Sketch::MinHash(mh)])
+                    .build(),
+            );
+        }
+        Ok(sigs)
+    }
+
+    /// Each identifier's lineage, as (rank, name) pairs.
+    pub fn lineages(&self) -> Vec<(&str, &[(String, String)])> {
+        self.json
+            .ident_to_idx
+            .iter()
+            .filter_map(|(ident, idx)| {
+                let lid = self.json.idx_to_lid.get(&idx.to_string())?;
+                let lineage = self.json.lid_to_lineage.get(&lid.to_string())?;
+                Some((ident.as_str(), lineage.as_slice()))
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,10 @@
+//! The parts of countergather other tools can use as a library.
+
+pub mod candidate;
+pub mod downsample;
+pub mod engine;
+pub mod index;
+pub mod result;
+pub mod split;
+
+pub use engine::{heap_gather, GatherEvent};
@@ -0,0 +1,164 @@
+//! Loading matchlist files with retries and a per-file timeout, for
+//! signature collections on flaky network filesystems.
+
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use log::{debug, warn};
+use sourmash::signature::Signature;
+
+use countergather::candidate::Candidate;
+use countergather::engine::GatherEvent;
+
+use crate::cache;
+
+/// How many files are loaded between `LoadProgress` events.
+const PROGRESS_EVERY: usize = 1000;
+
+/// How hard to try before giving up on a file.
+#[derive(Clone, Debug)]
+pub(crate) struct RetryPolicy {
+    /// attempts after the first one
+    pub(crate) retries: u32,
+    /// wait before the first retry; doubled for each further retry
+    pub(crate) backoff: Duration,
+    pub(crate) timeout: Option<Duration>,
+}
+
+/// One attempt, abandoned (but left running) after the timeout.
+fn attempt(
+    path: &Path,
+    cache_dir: Option<&Path>,
+    timeout: Option<Duration>,
+) -> Result<Vec<Signature>, String> {
+    let timeout = match timeout {
+        Some(timeout) => timeout,
+        None => return cache::load_signatures(path, cache_dir).map_err(|e| e.to_string()),
+    };
+
+    let (tx, rx) = mpsc::channel();
+    let path = path.to_path_buf();
+    let cache_dir = cache_dir.map(Path::to_path_buf);
+    std::thread::spawn(move || {
+        let result = cache::load_signatures(&path, cache_dir.as_deref()).map_err(|e| e.to_string());
+        // nobody is listening any more if we timed out.
+        let _ = tx.send(result);
+    });
+    match rx.recv_timeout(timeout) {
+        Ok(result) => result,
+        Err(_) => Err(format!("timed out after {:.1}s", timeout.as_secs_f64())),
+    }
+}
+
+/// Load the signatures in `path`, retrying failures with backoff.
+pub(crate) fn load_with_retry(
+    path: &Path,
+    cache_dir: Option<&Path>,
+    policy: &RetryPolicy,
+) -> Result<Vec<Signature>, String> {
+    let mut backoff = policy.backoff;
+    let mut n_retries = 0;
+    loop {
+        match attempt(path, cache_dir, policy.timeout) {
+            Ok(sigs) => return Ok(sigs),
+            Err(e) if n_retries < policy.retries => {
+                n_retries += 1;
+                debug!(
+                    "loading {} failed ({}); retry {} of {} in {:?}",
+                    path.display(),
+                    e,
+                    n_retries,
+                    policy.retries,
+                    backoff
+                );
+                std::thread::sleep(backoff);
+                backoff *= 2;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Loads matchlist files, remembering the ones that couldn't be loaded.
+pub(crate) struct Loader<'a> {
+    cache_dir: Option<PathBuf>,
+    policy: RetryPolicy,
+    failed: &'a Mutex<Vec<PathBuf>>,
+    n_failed: AtomicUsize,
+    /// where to report progress, if anywhere
+    on_event: Option<&'a (dyn Fn(GatherEvent) + Sync)>,
+    /// how many files there are to load, and how many were
+    total: usize,
+    n_loaded: AtomicUsize,
+}
+
+impl<'a> Loader<'a> {
+    pub(crate) fn new(
+        cache_dir: Option<&Path>,
+        policy: RetryPolicy,
+        failed: &'a Mutex<Vec<PathBuf>>,
+    ) -> Loader<'a> {
+        Loader {
+            cache_dir: cache_dir.map(Path::to_path_buf),
+            policy,
+            failed,
+            n_failed: AtomicUsize::new(0),
+            on_event: None,
+            total: 0,
+            n_loaded: AtomicUsize::new(0),
+        }
+    }
+
+    /// Report the loading of the next `total` files, and the candidates
+    /// found in them, to `on_event`.
+    pub(crate) fn with_progress(
+        mut self,
+        total: usize,
+        on_event: &'a (dyn Fn(GatherEvent) + Sync),
+    ) -> Loader<'a> {
+        self.on_event = Some(on_event);
+        self.total = total;
+        self.n_loaded = AtomicUsize::new(0);
+        self
+    }
+
+    /// Load the signatures in `path`; a file that still fails after the
+    /// retries is logged, recorded and skipped.
+    pub(crate) fn load(&self, path: &Path) -> Option<Vec<Signature>> {
+        let sigs = match load_with_retry(path, self.cache_dir.as_deref(), &self.policy) {
+            Ok(sigs) => Some(sigs),
+            Err(e) => {
+                warn!("could not load {}: {}", path.display(), e);
+                self.n_failed.fetch_add(1, Ordering::SeqCst);
+                self.failed.lock().unwrap().push(path.to_path_buf());
+                None
+            }
+        };
+        if let Some(on_event) = self.on_event {
+            // failed files count as loaded: there is nothing more to do.
+            let loaded = self.n_loaded.fetch_add(1, Ordering::SeqCst) + 1;
+            if loaded.is_multiple_of(PROGRESS_EVERY) || loaded == self.total {
+                on_event(GatherEvent::LoadProgress {
+                    loaded,
+                    total: self.total,
+                });
+            }
+        }
+        sigs
+    }
+
+    /// Report a candidate found in a loaded file.
+    pub(crate) fn found(&self, candidate: &Candidate) {
+        if let Some(on_event) = self.on_event {
+            on_event(GatherEvent::CandidateFound(candidate));
+        }
+    }
+
+    /// How many files this loader failed to load.
+    pub(crate) fn n_failed(&self) -> usize {
+        self.n_failed.load(Ordering::SeqCst)
+    }
+}
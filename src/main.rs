@@ -1,232 +1,2798 @@
-use clap::Parser;
+use clap::{Args, CommandFactory, FromArgMatches, Parser, Subcommand};
 
 use std::fs::File;
 use std::io::{BufRead, BufReader};
 use std::path::{Path, PathBuf};
 
-use std::collections::BinaryHeap;
+use std::collections::{BinaryHeap, HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
-use std::cmp::Ordering;
-use std::cmp::PartialOrd;
+use std::cmp::Reverse;
+use std::convert::TryFrom;
 
+use sourmash::encodings::HashFunctions;
 use sourmash::signature::{Signature, SigsTrait};
-use sourmash::sketch::minhash::{max_hash_for_scaled, KmerMinHash};
+use sourmash::sketch::minhash::{max_hash_for_scaled, scaled_for_max_hash, KmerMinHash};
 use sourmash::sketch::Sketch;
 
+use log::{debug, error, info, trace, warn};
 use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use countergather::candidate::{
+    intersect_hashes, jaccard, prefetch, weighted_overlap, Candidate, Label,
+};
+use countergather::downsample::{
+    prepare_query, prepare_query_any_scaled, prepare_query_any_scaled_with_source,
+    prepare_query_with_source, PrepareQueryError,
+};
+use countergather::engine::{self, threshold_hashes_for, GatherEvent};
+use countergather::index::{self, RankBy};
+use countergather::result::{ident_for_name, GatherResult};
+use countergather::split;
 
 // use std::collections::HashMap;
 
+mod background;
+mod bench;
+mod cache;
+mod check;
+mod cluster;
+mod collection;
+mod compat;
+mod compute;
+mod config;
+mod derep;
+mod describe;
+mod explain;
+#[cfg(feature = "grpc")]
+mod grpc;
+mod lazy;
+mod lca;
+mod loader;
+mod matrix;
+mod metrics;
+mod output;
+mod prefilter;
+mod redundancy;
+mod report;
+mod sbt;
+mod screen;
+mod search;
+mod serve;
+mod sigops;
+mod sigzip;
+mod stream;
+mod taxonomy;
+
 #[derive(Parser, Debug)]
-#[clap(author, version, about, long_about = None)]
+#[clap(author, version, about, long_about = None, after_help = EXIT_CODES_HELP)]
+#[clap(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Cli {
-    #[clap(parse(from_os_str))]
-    query: PathBuf,
+    #[clap(subcommand)]
+    command: Option<Command>,
+
+    /// more diagnostic output; repeat for even more
+    #[clap(short = 'v', long = "verbose", parse(from_occurrences), global = true)]
+    verbose: u8,
+
+    /// only report errors
+    #[clap(short = 'q', long = "quiet", global = true)]
+    quiet: bool,
+
+    /// TOML file of default parameters [default: $XDG_CONFIG_HOME/countergather/config.toml]
+    #[clap(
+        long = "config",
+        env = "COUNTERGATHER_CONFIG",
+        parse(from_os_str),
+        global = true
+    )]
+    config: Option<PathBuf>,
+
+    #[clap(flatten)]
+    gather: GatherArgs,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// print the metadata of each sketch in a signature file
+    Describe {
+        #[clap(parse(from_os_str))]
+        path: PathBuf,
+    },
+    /// validate the query and matchlist without running gather
+    Check(Box<GatherArgs>),
+    /// report every match above a containment or similarity threshold
+    Search(Box<search::SearchArgs>),
+    /// build scaled signatures from FASTA/FASTQ files
+    Sketch(compute::SketchArgs),
+    /// keep the hashes found in every signature
+    Intersect(sigops::SigOpArgs),
+    /// remove the hashes of the other signatures from the first one
+    Subtract(sigops::SigOpArgs),
+    /// combine the hashes of all signatures, summing abundances
+    Merge(sigops::SigOpArgs),
+    /// build a prefilter index of downsampled sketches for --prefilter
+    Index(prefilter::IndexArgs),
+    /// benchmark the gather engines on synthetic sketches
+    Bench(bench::BenchArgs),
+    /// compare every pair of signatures in a collection
+    Matrix(matrix::MatrixArgs),
+    /// greedily cluster a collection of signatures by containment
+    Cluster(cluster::ClusterArgs),
+    /// keep the matchlist loaded and answer gather queries over HTTP
+    Serve(serve::ServeArgs),
+    /// like `serve`, but streaming gather rounds over gRPC
+    #[cfg(feature = "grpc")]
+    ServeGrpc(serve::ServeArgs),
+}
+
+#[derive(Args, Debug, Clone)]
+struct GatherArgs {
+    /// query signature (optionally gzipped), FASTA/FASTQ file to sketch, or
+    /// `collection.zip::<name or md5>` to pick one from a sourmash zip
+    #[clap(
+        parse(from_os_str),
+        required_unless_present_any = &["query-list", "query-from-zip"]
+    )]
+    query: Option<PathBuf>,
+
+    /// file listing signature files to search, a sourmash zip collection,
+    /// a sourmash .sbt.zip, or a sourmash LCA database (.lca.json[.gz])
+    #[clap(
+        parse(from_os_str),
+        required_unless_present_any = &["query-list", "query-from-zip", "db"]
+    )]
+    matchlist: Option<PathBuf>,
+
+    /// another matchlist or database to search, merged with the others;
+    /// may be repeated, and the `db` column says where each match is from
+    #[clap(
+        long = "db",
+        env = "COUNTERGATHER_DB",
+        parse(from_os_str),
+        multiple_occurrences = true
+    )]
+    db: Vec<PathBuf>,
+
+    /// sourmash zip collection to take the query from; give only the
+    /// matchlist as a positional argument
+    #[clap(
+        long = "query-from-zip",
+        env = "COUNTERGATHER_QUERY_FROM_ZIP",
+        parse(from_os_str),
+        requires = "query-name",
+        conflicts_with = "query-list"
+    )]
+    query_from_zip: Option<PathBuf>,
+
+    /// name or md5 of the query signature in --query-from-zip
+    #[clap(
+        long = "query-name",
+        env = "COUNTERGATHER_QUERY_NAME",
+        requires = "query-from-zip"
+    )]
+    query_name: Option<String>,
+
+    /// file listing query paths to gather independently, in parallel; give
+    /// only the matchlist as a positional argument
+    #[clap(
+        long = "query-list",
+        env = "COUNTERGATHER_QUERY_LIST",
+        parse(from_os_str),
+        requires = "output-dir",
+        conflicts_with_all = &[
+            "output", "output-prefetch", "output-hashes", "output-unassigned",
+            "output-found", "output-redundancy", "save-query-every", "report"
+        ]
+    )]
+    query_list: Option<PathBuf>,
+
+    /// directory for the <query>.gather.csv of each --query-list query;
+    /// queries with the same basename get a short md5 of their path added
+    #[clap(
+        long = "output-dir",
+        env = "COUNTERGATHER_OUTPUT_DIR",
+        parse(from_os_str),
+        requires = "query-list"
+    )]
+    output_dir: Option<PathBuf>,
+
+    /// skip the --query-list queries already completed in --output-dir,
+    /// to resume an interrupted batch; each finished gather leaves a
+    /// <query>.done marker there, with or without matches
+    #[clap(
+        long = "skip-completed",
+        env = "COUNTERGATHER_SKIP_COMPLETED",
+        requires = "query-list"
+    )]
+    skip_completed: bool,
+
+    /// sketch every record of a FASTA/FASTQ query on its own and gather
+    /// the records independently, in parallel, writing all the results to
+    /// --output with a `contig` column
+    #[clap(
+        long = "per-contig",
+        env = "COUNTERGATHER_PER_CONTIG",
+        requires = "output",
+        conflicts_with_all = &[
+            "query-list", "query-from-zip", "output-prefetch", "output-hashes",
+            "output-unassigned", "output-found", "output-redundancy", "output-derep",
+            "save-query-every", "save-round-diffs", "report", "compat", "taxonomy",
+            "lazy-load", "no-prefetch", "early-start-bp"
+        ]
+    )]
+    per_contig: bool,
+
+    /// with --per-contig, the sketched record gathered by this run
+    #[clap(skip)]
+    contig_query: Option<Signature>,
+
+    /// number of threads to use; by default, one per CPU
+    #[clap(long = "threads", env = "COUNTERGATHER_THREADS")]
+    threads: Option<usize>,
+
+    /// check that the results don't depend on the threads: gather again on
+    /// a single thread and fail if any round picked a different match
+    #[clap(
+        long = "deterministic",
+        env = "COUNTERGATHER_DETERMINISTIC",
+        conflicts_with_all = &["lazy-load", "no-prefetch"]
+    )]
+    deterministic: bool,
+
+    #[clap(flatten)]
+    template: TemplateArgs,
+
+    /// downsample the query to the coarsest scaled in the matchlist
+    #[clap(long = "auto-downsample", env = "COUNTERGATHER_AUTO_DOWNSAMPLE")]
+    auto_downsample: bool,
+
+    /// track abundances when sketching a FASTA/FASTQ query
+    #[clap(long = "track-abundance", env = "COUNTERGATHER_TRACK_ABUNDANCE")]
+    track_abundance: bool,
+
+    /// flatten query abundances and treat every hash as seen once
+    #[clap(
+        long = "ignore-abundance",
+        env = "COUNTERGATHER_IGNORE_ABUNDANCE",
+        conflicts_with = "track-abundance"
+    )]
+    ignore_abundance: bool,
+
+    /// print a sourmash-style table of results to stdout
+    #[clap(long = "report", env = "COUNTERGATHER_REPORT")]
+    report: bool,
+
+    /// use color in the --report table
+    #[clap(long = "color", env = "COUNTERGATHER_COLOR", requires = "report")]
+    color: bool,
+
+    /// load the matchlist in chunks to stay within this much memory, e.g.
+    /// 32G, 32GB or 32GiB (all 32 * 1024^3 bytes)
+    #[clap(
+        long = "max-memory",
+        env = "COUNTERGATHER_MAX_MEMORY",
+        parse(try_from_str = parse_memory),
+        conflicts_with = "auto-downsample"
+    )]
+    max_memory: Option<u64>,
+
+    /// keep only a summary of each match in memory, reloading sketches
+    /// from disk when they are needed
+    #[clap(
+        long = "lazy-load",
+        env = "COUNTERGATHER_LAZY_LOAD",
+        conflicts_with_all = &["auto-downsample", "max-memory"]
+    )]
+    lazy_load: bool,
+
+    /// keep no candidates in memory: rescan the matchlist files from disk
+    /// every round, keeping only the best match; slow, but uses little
+    /// memory
+    #[clap(
+        long = "no-prefetch",
+        env = "COUNTERGATHER_NO_PREFETCH",
+        conflicts_with_all = &[
+            "auto-downsample",
+            "max-memory",
+            "lazy-load",
+            "speculate",
+            "output-prefetch",
+        ]
+    )]
+    no_prefetch: bool,
+
+    /// number of reloaded sketches to keep cached with --lazy-load
+    #[clap(
+        long = "lazy-cache-size",
+        env = "COUNTERGATHER_LAZY_CACHE_SIZE",
+        default_value = "16",
+        requires = "lazy-load"
+    )]
+    lazy_cache_size: usize,
+
+    /// keep a binary copy of parsed matchlist sketches in this directory,
+    /// for faster loading next time
+    #[clap(
+        long = "cache-dir",
+        env = "COUNTERGATHER_CACHE_DIR",
+        parse(from_os_str)
+    )]
+    cache_dir: Option<PathBuf>,
+
+    /// after each round, rescore only the top K candidates in parallel,
+    /// falling back to rescoring all of them when that isn't enough
+    #[clap(
+        long = "speculate",
+        env = "COUNTERGATHER_SPECULATE",
+        conflicts_with = "lazy-load"
+    )]
+    speculate: Option<usize>,
+
+    /// after each round, drop the candidates that share at least this
+    /// fraction of their remaining overlap with the selected match, e.g.
+    /// 0.95 for near-identical strains; they would only add tiny rounds
+    #[clap(
+        long = "prune-contained",
+        env = "COUNTERGATHER_PRUNE_CONTAINED",
+        parse(try_from_str = parse_fraction),
+        conflicts_with_all = &["lazy-load", "no-prefetch"]
+    )]
+    prune_contained: Option<f64>,
+
+    /// before gather, collapse candidates whose containment ANI with a
+    /// better candidate is at least this, both ways, into that candidate
+    #[clap(
+        long = "derep-threshold",
+        env = "COUNTERGATHER_DEREP_THRESHOLD",
+        parse(try_from_str = parse_fraction),
+        conflicts_with_all = &["lazy-load", "no-prefetch", "max-memory"]
+    )]
+    derep_threshold: Option<f64>,
+
+    /// split the hashes shared by matches whose overlaps are within this
+    /// many standard deviations of the best one's between them, rather
+    /// than crediting them all to the one picked first; e.g. 2
+    #[clap(
+        long = "fair-split",
+        env = "COUNTERGATHER_FAIR_SPLIT",
+        conflicts_with_all = &["lazy-load", "no-prefetch", "speculate", "deterministic"]
+    )]
+    fair_split: Option<f64>,
+
+    /// shortlist matchlist files by a first prefetch at this coarser
+    /// scaled, then load only those at --scaled (--fine-scaled)
+    #[clap(long = "coarse-scaled", env = "COUNTERGATHER_COARSE_SCALED")]
+    coarse_scaled: Option<u64>,
+
+    /// retry matchlist files that fail to load this many times
+    #[clap(
+        long = "load-retries",
+        env = "COUNTERGATHER_LOAD_RETRIES",
+        default_value = "0"
+    )]
+    load_retries: u32,
+
+    /// milliseconds to wait before the first retry, doubled for each
+    /// further retry
+    #[clap(
+        long = "retry-backoff-ms",
+        env = "COUNTERGATHER_RETRY_BACKOFF_MS",
+        default_value = "200"
+    )]
+    retry_backoff_ms: u64,
+
+    /// give up on a matchlist file after this many seconds
+    #[clap(
+        long = "load-timeout",
+        env = "COUNTERGATHER_LOAD_TIMEOUT",
+        parse(try_from_str = parse_positive)
+    )]
+    load_timeout: Option<f64>,
+
+    /// start gather while the matchlist is still loading, trusting that no
+    /// file yet to load overlaps the query by more than this many bp;
+    /// rounds whose best loaded match overlaps by less wait for the rest
+    #[clap(
+        long = "early-start-bp",
+        env = "COUNTERGATHER_EARLY_START_BP",
+        conflicts_with_all = &[
+            "lazy-load", "no-prefetch", "max-memory", "auto-downsample", "derep-threshold",
+            "deterministic", "explain-match", "fair-split", "prune-contained", "output-prefetch"
+        ]
+    )]
+    early_start_bp: Option<u64>,
+
+    /// with --early-start-bp, load at most this many matchlist files per
+    /// second, to go easy on a shared filesystem
+    #[clap(
+        long = "load-rate",
+        env = "COUNTERGATHER_LOAD_RATE",
+        requires = "early-start-bp",
+        parse(try_from_str = parse_positive)
+    )]
+    load_rate: Option<f64>,
+
+    /// index built by `countergather index`; matchlist files that can't
+    /// reach the threshold according to it are not loaded
+    #[clap(
+        long = "prefilter",
+        env = "COUNTERGATHER_PREFILTER",
+        parse(from_os_str)
+    )]
+    prefilter: Option<PathBuf>,
+
+    /// leave matches with less than this many bp unique to them out of
+    /// the results and every other output; their hashes stay unassigned
+    #[clap(long = "min-unique-bp", env = "COUNTERGATHER_MIN_UNIQUE_BP")]
+    min_unique_bp: Option<u64>,
+
+    /// stop once this fraction of the query is assigned; weighted by
+    /// abundance when the query has abundances
+    #[clap(long = "stop-at-fraction", env = "COUNTERGATHER_STOP_AT_FRACTION")]
+    stop_at_fraction: Option<f64>,
+
+    /// pick each match by overlap with the remaining query, by the
+    /// fraction of the match in it, or by abundance-weighted overlap
+    #[clap(
+        long = "rank-by",
+        env = "COUNTERGATHER_RANK_BY",
+        default_value = "overlap",
+        possible_values = &["overlap", "containment", "weighted"]
+    )]
+    rank_by: String,
+
+    /// minimum overlap with the query, in bp, for a match to be reported
+    #[clap(
+        long = "threshold-bp",
+        env = "COUNTERGATHER_THRESHOLD_BP",
+        default_value = "0"
+    )]
+    threshold_bp: u64,
+
+    /// write gather results to this CSV file; sorted by rank, rows are
+    /// written as they are found, so an interrupted run leaves the rows so far
+    #[clap(
+        short = 'o',
+        long = "output",
+        env = "COUNTERGATHER_OUTPUT",
+        parse(from_os_str)
+    )]
+    output: Option<PathBuf>,
+
+    /// order of the rows in the -o CSV: gather rank (as they are found),
+    /// overlap with the query, ANI or name; rows are only written at the
+    /// end when not sorted by rank
+    #[clap(
+        long = "sort-by",
+        env = "COUNTERGATHER_SORT_BY",
+        default_value = "rank",
+        possible_values = &["rank", "overlap", "ani", "name"],
+        conflicts_with = "compat"
+    )]
+    sort_by: String,
+
+    /// write the initial containment of every candidate match to this CSV
+    #[clap(
+        long = "output-prefetch",
+        env = "COUNTERGATHER_OUTPUT_PREFETCH",
+        parse(from_os_str)
+    )]
+    output_prefetch: Option<PathBuf>,
+
+    /// how the prefetch CSV and `search` rank matches, and what the search
+    /// threshold applies to: containment of the query, containment of the
+    /// smaller sketch in the larger one, or Jaccard similarity
+    #[clap(
+        long = "metric",
+        env = "COUNTERGATHER_METRIC",
+        default_value = "containment",
+        possible_values = &["containment", "max-containment", "jaccard"]
+    )]
+    metric: String,
+
+    /// stop after writing the prefetch CSV, without running gather
+    #[clap(
+        long = "no-gather",
+        env = "COUNTERGATHER_NO_GATHER",
+        requires = "output-prefetch"
+    )]
+    no_gather: bool,
+
+    /// write the match assigned to each query hash to this CSV (.gz ok)
+    #[clap(
+        long = "output-hashes",
+        env = "COUNTERGATHER_OUTPUT_HASHES",
+        parse(from_os_str)
+    )]
+    output_hashes: Option<PathBuf>,
+
+    /// write how much each match shares with the matches ranked above it
+    /// to this CSV
+    #[clap(
+        long = "output-redundancy",
+        env = "COUNTERGATHER_OUTPUT_REDUNDANCY",
+        parse(from_os_str)
+    )]
+    output_redundancy: Option<PathBuf>,
+
+    /// write which representative every candidate was collapsed into by
+    /// --derep-threshold to this CSV
+    #[clap(
+        long = "output-derep",
+        env = "COUNTERGATHER_OUTPUT_DEREP",
+        parse(from_os_str),
+        requires = "derep-threshold"
+    )]
+    output_derep: Option<PathBuf>,
+
+    /// write the query hashes not assigned to any match as a signature
+    #[clap(
+        long = "output-unassigned",
+        env = "COUNTERGATHER_OUTPUT_UNASSIGNED",
+        parse(from_os_str)
+    )]
+    output_unassigned: Option<PathBuf>,
 
-    #[clap(parse(from_os_str))]
-    matchlist: PathBuf,
+    /// write the query hashes assigned to some match as a signature
+    #[clap(
+        long = "output-found",
+        env = "COUNTERGATHER_OUTPUT_FOUND",
+        parse(from_os_str)
+    )]
+    output_found: Option<PathBuf>,
+
+    /// save the remaining query every N gather rounds
+    #[clap(
+        long = "save-query-every",
+        env = "COUNTERGATHER_SAVE_QUERY_EVERY",
+        requires = "save-query-prefix"
+    )]
+    save_query_every: Option<usize>,
+
+    /// path prefix for saved queries; files are named <prefix>.<round>.sig[.gz]
+    #[clap(
+        long = "save-query-prefix",
+        env = "COUNTERGATHER_SAVE_QUERY_PREFIX",
+        parse(from_os_str),
+        requires = "save-query-every"
+    )]
+    save_query_prefix: Option<PathBuf>,
+
+    /// how to write match names in the `name` column and the report:
+    /// the full name, or only its identifier (the first word)
+    #[clap(
+        long = "name-format",
+        env = "COUNTERGATHER_NAME_FORMAT",
+        default_value = "full",
+        possible_values = &["full", "ident"]
+    )]
+    name_format: String,
+
+    /// truncate match names in the `name` column and the report to this
+    /// many characters
+    #[clap(long = "name-max-length", env = "COUNTERGATHER_NAME_MAX_LENGTH")]
+    name_max_length: Option<usize>,
+
+    /// write the query hashes removed in each round, as a signature named
+    /// after the match, to this zip collection
+    #[clap(
+        long = "save-round-diffs",
+        env = "COUNTERGATHER_SAVE_ROUND_DIFFS",
+        parse(from_os_str)
+    )]
+    save_round_diffs: Option<PathBuf>,
+
+    /// after gather, gather again following the match with this name, ident
+    /// or md5 (prefix), writing the query hashes it lost to higher-ranked
+    /// matches and who took them to --explain-prefix
+    #[clap(
+        long = "explain-match",
+        env = "COUNTERGATHER_EXPLAIN_MATCH",
+        requires = "explain-prefix",
+        conflicts_with_all = &["query-list", "lazy-load", "no-prefetch", "fair-split", "per-contig"]
+    )]
+    explain_match: Option<String>,
+
+    /// path prefix for --explain-match; writes <prefix>.lost.sig[.gz] and
+    /// <prefix>.csv
+    #[clap(
+        long = "explain-prefix",
+        env = "COUNTERGATHER_EXPLAIN_PREFIX",
+        parse(from_os_str),
+        requires = "explain-match"
+    )]
+    explain_prefix: Option<PathBuf>,
+
+    /// lay out the -o CSV like another tool's gather output
+    #[clap(
+        long = "compat",
+        env = "COUNTERGATHER_COMPAT",
+        possible_values = &["sourmash"],
+        requires = "output"
+    )]
+    compat: Option<String>,
+
+    /// write a JSON summary of the run, with its wall time, CPU time and
+    /// peak memory
+    #[clap(
+        long = "summary-json",
+        env = "COUNTERGATHER_SUMMARY_JSON",
+        parse(from_os_str)
+    )]
+    summary_json: Option<PathBuf>,
+
+    /// gzip-compress the signatures written by --output-unassigned,
+    /// --output-found and --save-query-every, adding `.gz` to their names
+    #[clap(long = "gzip-output", env = "COUNTERGATHER_GZIP_OUTPUT")]
+    gzip_output: bool,
+
+    /// lineage spreadsheet or sourmash LCA database used to summarize
+    /// results by rank; needs -o
+    #[clap(
+        long = "taxonomy",
+        env = "COUNTERGATHER_TAXONOMY",
+        parse(from_os_str),
+        requires = "output"
+    )]
+    taxonomy: Option<PathBuf>,
+}
+
+#[derive(Args, Debug, Clone)]
+struct TemplateArgs {
+    /// k-mer size, in amino acids for protein-ish moltypes; detected from
+    /// the query if not given
+    #[clap(short = 'k', long = "ksize", env = "COUNTERGATHER_KSIZE")]
+    ksize: Option<u32>,
+
+    /// scaled value; detected from the query if not given
+    #[clap(
+        short = 's',
+        long = "scaled",
+        env = "COUNTERGATHER_SCALED",
+        visible_alias = "fine-scaled"
+    )]
+    scaled: Option<u64>,
+
+    /// molecule type; detected from the query if not given
+    #[clap(
+        long = "moltype",
+        env = "COUNTERGATHER_MOLTYPE",
+        possible_values = &["DNA", "protein", "dayhoff", "hp"]
+    )]
+    moltype: Option<String>,
+
+    /// hash seed used to build the sketches
+    #[clap(long = "seed", env = "COUNTERGATHER_SEED", default_value = "42")]
+    seed: u64,
 }
 
-fn check_compatible_downsample(
-    me: &KmerMinHash,
-    other: &KmerMinHash,
-) -> Result<(), sourmash::Error> {
-    /*
-    if self.num != other.num {
-        return Err(Error::MismatchNum {
-            n1: self.num,
-            n2: other.num,
+impl GatherArgs {
+    /// With `--query-list` or `--query-from-zip` the single positional
+    /// argument is the matchlist; move it there. `--query-from-zip` and
+    /// `--query-name` become a `collection.zip::name` query.
+    fn resolve_positionals(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        if self.query_list.is_some() {
+            if self.matchlist.is_some() {
+                return Err("with --query-list, give only the matchlist".into());
+            }
+            self.matchlist = self.query.take();
         }
-        .into());
+        if let (Some(zip_path), Some(name)) = (&self.query_from_zip, &self.query_name) {
+            if self.matchlist.is_some() {
+                return Err("with --query-from-zip, give only the matchlist".into());
+            }
+            let mut query = zip_path.clone().into_os_string();
+            query.push("::");
+            query.push(name);
+            self.matchlist = self.query.replace(PathBuf::from(query));
+        }
+        Ok(())
     }
-    */
-    use sourmash::Error;
 
-    if me.ksize() != other.ksize() {
-        return Err(Error::MismatchKSizes);
+    fn retry_policy(&self) -> loader::RetryPolicy {
+        loader::RetryPolicy {
+            retries: self.load_retries,
+            backoff: Duration::from_millis(self.retry_backoff_ms),
+            timeout: self.load_timeout.map(Duration::from_secs_f64),
+        }
     }
-    if me.hash_function() != other.hash_function() {
-        // TODO: fix this error
-        return Err(Error::MismatchDNAProt);
+
+    /// A match name as `--name-format` and `--name-max-length` render it.
+    fn format_name(&self, name: &str) -> String {
+        let name = match self.name_format.as_str() {
+            "ident" => ident_for_name(name),
+            _ => name,
+        };
+        match self.name_max_length {
+            Some(max) => name.chars().take(max).collect(),
+            None => name.to_string(),
+        }
     }
-    if me.max_hash() < other.max_hash() {
-        return Err(Error::MismatchScaled);
+
+    /// Whether a match with `unique_bp` of its own is left out by
+    /// `--min-unique-bp`.
+    fn below_min_unique(&self, unique_bp: u64) -> bool {
+        self.min_unique_bp.is_some_and(|min| unique_bp < min)
     }
-    if me.seed() != other.seed() {
-        return Err(Error::MismatchSeed);
+
+    /// Where to write a signature output, following `--gzip-output`.
+    fn signature_path(&self, path: &Path) -> PathBuf {
+        if self.gzip_output && path.extension().is_none_or(|ext| ext != "gz") {
+            let mut path = path.to_path_buf().into_os_string();
+            path.push(".gz");
+            PathBuf::from(path)
+        } else {
+            path.to_path_buf()
+        }
     }
-    Ok(())
 }
 
-fn prepare_query(search_sig: &Signature, template: &Sketch) -> Option<KmerMinHash> {
-    let mut search_mh = None;
-    if let Some(Sketch::MinHash(mh)) = search_sig.select_sketch(template) {
-        search_mh = Some(mh.clone());
-    } else {
-        // try to find one that can be downsampled
-        if let Sketch::MinHash(template_mh) = template {
-            for sketch in search_sig.sketches() {
-                if let Sketch::MinHash(ref_mh) = sketch {
-                    if check_compatible_downsample(&ref_mh, template_mh).is_ok() {
-                        let max_hash = max_hash_for_scaled(template_mh.scaled());
-                        let mh = ref_mh.downsample_max_hash(max_hash).unwrap();
-                        return Some(mh);
-                    }
-                }
+impl TemplateArgs {
+    fn moltype(&self) -> Result<Option<HashFunctions>, sourmash::Error> {
+        match self.moltype {
+            Some(ref m) => Ok(Some(HashFunctions::try_from(m.as_str())?)),
+            None => Ok(None),
+        }
+    }
+}
+
+/// The name and sketch of the query signature fitting the template, and
+/// the sketch it was downsampled from, if it was.
+fn select_query(
+    sigs: &[Signature],
+    template: &Sketch,
+) -> Result<(String, KmerMinHash, Option<KmerMinHash>), NoCompatibleQuery> {
+    let mut selected = Err(PrepareQueryError::NoCompatibleSketch(vec![]));
+    for sig in sigs {
+        match prepare_query_with_source(sig, template) {
+            Ok((mh, source)) => {
+                selected = Ok((sig.name(), mh, source));
+                // doesn't this pick the last one to match the template:
+                // hmm. @CTB
             }
+            Err(e) if selected.is_err() => selected = Err(e),
+            Err(_) => {}
         }
     }
-    search_mh
+    selected.map_err(|e| NoCompatibleQuery(format!("query has no usable sketch: {}", e)))
 }
 
-struct PrefetchResult {
-    name: String,
-    minhash: KmerMinHash,
-    containment: u64,
+const EXIT_ERROR: i32 = 1;
+const EXIT_NO_COMPATIBLE_QUERY: i32 = 3;
+const EXIT_NO_MATCHES: i32 = 4;
+const EXIT_PARTIAL_LOAD: i32 = 5;
+
+const EXIT_CODES_HELP: &str = "EXIT CODES:
+    0    success; matches were found
+    1    error
+    2    invalid command line
+    3    no single query sketch compatible with the requested parameters
+    4    no matches above threshold
+    5    some matchlist entries could not be loaded, with or without matches";
+
+/// How a gather run finished; mapped onto the process exit code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum GatherStatus {
+    Matched,
+    NoMatches,
+    PartialLoad,
 }
 
-impl Ord for PrefetchResult {
-    fn cmp(&self, other: &PrefetchResult) -> Ordering {
-        self.containment.cmp(&other.containment)
+impl GatherStatus {
+    fn name(&self) -> &'static str {
+        match self {
+            GatherStatus::Matched => "matched",
+            GatherStatus::NoMatches => "no_matches",
+            GatherStatus::PartialLoad => "partial_load",
+        }
+    }
+
+    /// The status of a run that found nothing: a failed load may have hidden
+    /// matches, so it takes precedence.
+    fn or_no_matches(self) -> GatherStatus {
+        match self {
+            GatherStatus::PartialLoad => GatherStatus::PartialLoad,
+            _ => GatherStatus::NoMatches,
+        }
+    }
+
+    fn exit_code(&self) -> i32 {
+        match self {
+            GatherStatus::Matched => 0,
+            GatherStatus::NoMatches => EXIT_NO_MATCHES,
+            GatherStatus::PartialLoad => EXIT_PARTIAL_LOAD,
+        }
     }
 }
 
-impl PartialOrd for PrefetchResult {
-    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        Some(self.cmp(other))
+/// The query has no sketch that can be used with the template.
+#[derive(Debug)]
+struct NoCompatibleQuery(String);
+
+impl std::fmt::Display for NoCompatibleQuery {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.0)
     }
 }
 
-impl PartialEq for PrefetchResult {
-    fn eq(&self, other: &Self) -> bool {
-        self.containment == other.containment
+impl std::error::Error for NoCompatibleQuery {}
+
+/// The ksize of a sketch as it is given on the command line: protein-ish
+/// ksizes are stored multiplied by 3 in the sketches.
+fn ksize_of(mh: &KmerMinHash) -> u32 {
+    match mh.hash_function() {
+        HashFunctions::murmur64_DNA => mh.ksize() as u32,
+        _ => mh.ksize() as u32 / 3,
     }
 }
 
-impl Eq for PrefetchResult {}
+/// A sketch's parameters as they are given on the command line.
+fn describe_sketch(mh: &KmerMinHash) -> String {
+    let size = if mh.num() != 0 {
+        format!("num={}", mh.num())
+    } else {
+        format!("scaled={}", mh.scaled())
+    };
+    format!(
+        "k={} {} moltype={} seed={}",
+        ksize_of(mh),
+        size,
+        compat::moltype_name(mh.hash_function()),
+        mh.seed()
+    )
+}
 
-fn prefetch(
-    query: &KmerMinHash,
-    sketchlist: BinaryHeap<PrefetchResult>,
-) -> BinaryHeap<PrefetchResult> {
-    sketchlist
-        .into_par_iter()
-        .filter_map(|result| {
-            let mut mm = None;
-            let searchsig = &result.minhash;
-            let containment = searchsig.count_common(query, false);
-            if let Ok(containment) = containment {
-                if containment > 0 {
-                    let result = PrefetchResult {
-                        containment,
-                        ..result
-                    };
-                    mm = Some(result);
-                }
-            }
-            mm
+/// Explain why none of the query sketches fit the requested parameters:
+/// what was asked for, what the query has, and which option to change.
+fn incompatible_query_message(
+    query_sigs: &[Signature],
+    ksize: Option<u32>,
+    scaled: Option<u64>,
+    moltype: Option<HashFunctions>,
+    seed: u64,
+) -> String {
+    let sketches: Vec<KmerMinHash> = query_sigs
+        .iter()
+        .flat_map(|sig| sig.sketches())
+        .filter_map(|sketch| match sketch {
+            Sketch::MinHash(mh) => Some(mh),
+            _ => None,
         })
-        .collect()
+        .collect();
+    if sketches.is_empty() {
+        return "the query has no sketches".into();
+    }
+
+    let mut requested = vec![];
+    if let Some(ksize) = ksize {
+        requested.push(format!("k={}", ksize));
+    }
+    if let Some(scaled) = scaled {
+        requested.push(format!("scaled={}", scaled));
+    }
+    if let Some(moltype) = moltype {
+        requested.push(format!("moltype={}", compat::moltype_name(moltype)));
+    }
+    requested.push(format!("seed={}", seed));
+
+    let mut found: Vec<String> = sketches.iter().map(describe_sketch).collect();
+    found.sort();
+    found.dedup();
+
+    format!(
+        "no query sketch matches the requested parameters ({}); the query has {}; {}",
+        requested.join(" "),
+        found.join(", "),
+        fix_hint(&sketches, ksize, moltype, seed)
+    )
 }
 
-fn do_countergather<P: AsRef<Path> + std::fmt::Debug>(
-    query_filename: P,
-    matchlist: P,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let max_hash = max_hash_for_scaled(100000_u64);
+/// The option to change for some query sketch to be usable, going through
+/// the requirements in the order `build_template` checks them.
+fn fix_hint(
+    sketches: &[KmerMinHash],
+    ksize: Option<u32>,
+    moltype: Option<HashFunctions>,
+    seed: u64,
+) -> String {
+    let choices = |left: &[&KmerMinHash], value: &dyn Fn(&KmerMinHash) -> String| {
+        let mut values: Vec<String> = left.iter().map(|mh| value(mh)).collect();
+        values.sort();
+        values.dedup();
+        values.join(" or ")
+    };
+
+    let mut left: Vec<&KmerMinHash> = sketches.iter().filter(|mh| mh.num() == 0).collect();
+    if left.is_empty() {
+        return "the query only has num sketches; sketch it with scaled instead".into();
+    }
+    if !left.iter().any(|mh| mh.seed() == seed) {
+        return format!("try --seed {}", choices(&left, &|mh| mh.seed().to_string()));
+    }
+    left.retain(|mh| mh.seed() == seed);
+    if let Some(moltype) = moltype {
+        if !left.iter().any(|mh| mh.hash_function() == moltype) {
+            return format!(
+                "try --moltype {}",
+                choices(&left, &|mh| compat::moltype_name(mh.hash_function()).into())
+            );
+        }
+        left.retain(|mh| mh.hash_function() == moltype);
+    }
+    if let Some(ksize) = ksize {
+        if !left.iter().any(|mh| ksize_of(mh) == ksize) {
+            return format!(
+                "try --ksize {}",
+                choices(&left, &|mh| ksize_of(mh).to_string())
+            );
+        }
+        left.retain(|mh| ksize_of(mh) == ksize);
+    }
+    // only the scaled is left: the query can't be upsampled.
+    let min_scaled = left.iter().map(|mh| mh.scaled()).min().unwrap_or(0);
+    format!("try --scaled {} or larger", min_scaled)
+}
+
+/// Build the template sketch used to select query and matchlist sketches.
+///
+/// Any of ksize/scaled/moltype that are not given are taken from the query
+/// sketches; it is an error if that leaves more than one candidate.
+fn build_template(
+    query_sigs: &[Signature],
+    ksize: Option<u32>,
+    scaled: Option<u64>,
+    moltype: Option<HashFunctions>,
+    seed: u64,
+) -> Result<Sketch, Box<dyn std::error::Error>> {
+    let mut candidates: Vec<(u32, u64, HashFunctions)> = vec![];
+    for sig in query_sigs {
+        for sketch in sig.sketches() {
+            if let Sketch::MinHash(mh) = sketch {
+                if mh.num() != 0 || mh.seed() != seed {
+                    continue;
+                }
+                // without --moltype, -k 10 also selects a k=10 protein sketch.
+                if ksize.is_some_and(|k| k != ksize_of(&mh)) {
+                    continue;
+                }
+                if moltype.is_some() && moltype != Some(mh.hash_function()) {
+                    continue;
+                }
+                if let Some(scaled) = scaled {
+                    // the query must be downsampled to the template scaled.
+                    if mh.scaled() > scaled {
+                        continue;
+                    }
+                }
+
+                // use the query's own max_hash so that it is selected as-is.
+                let max_hash = scaled.map_or_else(|| mh.max_hash(), max_hash_for_scaled);
+                let candidate = (mh.ksize() as u32, max_hash, mh.hash_function());
+                if !candidates.contains(&candidate) {
+                    candidates.push(candidate);
+                }
+            }
+        }
+    }
+
+    if candidates.is_empty() {
+        return Err(NoCompatibleQuery(incompatible_query_message(
+            query_sigs, ksize, scaled, moltype, seed,
+        ))
+        .into());
+    }
+    if candidates.len() > 1 {
+        let found: Vec<String> = candidates
+            .iter()
+            .map(|(k, m, h)| format!("k={} scaled={} moltype={}", k, scaled_for_max_hash(*m), h))
+            .collect();
+        return Err(NoCompatibleQuery(format!(
+            "query has multiple candidate sketches ({}); please specify --ksize/--scaled/--moltype",
+            found.join(", ")
+        ))
+        .into());
+    }
+
+    let (ksize, max_hash, hash_function) = candidates[0];
     let template_mh = KmerMinHash::builder()
         .num(0u32)
-        .ksize(31_u32)
+        .ksize(ksize)
+        .hash_function(hash_function)
+        .seed(seed)
         .max_hash(max_hash)
         .build();
-    let template = Sketch::MinHash(template_mh);
+    Ok(Sketch::MinHash(template_mh))
+}
+
+/// Does this path look like a FASTA/FASTQ file, rather than a signature?
+fn is_sequence_file<P: AsRef<Path>>(path: P) -> bool {
+    let name = path.as_ref().to_string_lossy().to_lowercase();
+    let name = name.strip_suffix(".gz").unwrap_or(&name);
+    [".fa", ".fasta", ".fna", ".fq", ".fastq"]
+        .iter()
+        .any(|ext| name.ends_with(ext))
+}
 
-    println!("Loading query");
-    let mut query = {
-        let sigs = Signature::from_path(dbg!(query_filename)).unwrap();
+/// The empty sketch FASTA/FASTQ queries are sketched into: the template
+/// parameters, defaulting to k=31, scaled=1000 and DNA when not given.
+fn query_sketch_template(args: &GatherArgs) -> Result<KmerMinHash, Box<dyn std::error::Error>> {
+    Ok(compute::empty_minhash(
+        args.template.ksize.unwrap_or(31),
+        args.template.scaled.unwrap_or(1000),
+        args.template
+            .moltype()?
+            .unwrap_or(HashFunctions::murmur64_DNA),
+        args.template.seed,
+        args.track_abundance,
+    ))
+}
 
-        let mut mm = None;
-        for sig in &sigs {
-            if let Some(mh) = prepare_query(sig, &template) {
-                mm = Some(mh.clone());
-                // doesn't this pick the last one to match the template:
-                // hmm. @CTB
+/// Load the query signatures, sketching FASTA/FASTQ input on the fly.
+fn load_query_sigs<P: AsRef<Path>>(
+    path: P,
+    args: &GatherArgs,
+) -> Result<Vec<Signature>, Box<dyn std::error::Error>> {
+    let path = path.as_ref();
+    if let Some(sig) = &args.contig_query {
+        Ok(vec![sig.clone()])
+    } else if let Some((zip_path, selector)) = sigzip::parse_selection(path) {
+        info!("Selecting query {} from {}", selector, zip_path.display());
+        sigzip::load_selection(zip_path, selector)
+    } else if is_sequence_file(path) {
+        info!("Sketching query from {}", path.display());
+        Ok(vec![compute::sketch_file(
+            path,
+            &query_sketch_template(args)?,
+            false,
+        )?])
+    } else {
+        Ok(Signature::from_path(path)?)
+    }
+}
+
+/// The sketch paths to match against, with any labels from a CSV matchlist.
+#[derive(Clone, Debug, Default)]
+struct Matchlist {
+    paths: Vec<PathBuf>,
+    labels: HashMap<PathBuf, Label>,
+}
+
+#[derive(Deserialize)]
+struct MatchlistRow {
+    path: PathBuf,
+    #[serde(default)]
+    name: Option<String>,
+    #[serde(default)]
+    group: Option<String>,
+}
+
+/// Read the list of sketch paths to match against: one per line, or a
+/// `.csv` file with a `path` column and optional `name` and `group`
+/// columns. Lines starting with `#` are comments.
+fn load_matchlist<P: AsRef<Path>>(matchlist: P) -> Result<Matchlist, std::io::Error> {
+    let matchlist = matchlist.as_ref();
+    let mut list = Matchlist::default();
+
+    if matchlist.extension().is_some_and(|ext| ext == "csv") {
+        let mut reader = csv::ReaderBuilder::new()
+            .comment(Some(b'#'))
+            .from_path(matchlist)?;
+        for row in reader.deserialize() {
+            let row: MatchlistRow = row?;
+            let label = Label {
+                name: row.name.filter(|name| !name.is_empty()),
+                group: row.group.filter(|group| !group.is_empty()),
+                db: None,
+            };
+            if label.name.is_some() || label.group.is_some() {
+                list.labels.insert(row.path.clone(), label);
             }
+            list.paths.push(row.path);
         }
-        mm
+        return Ok(list);
     }
-    .unwrap();
 
-    println!("Loading matchlist");
     let matchlist_file = BufReader::new(File::open(matchlist)?);
+    for line in matchlist_file.lines() {
+        let line = line?;
+        // skip empty lines and comments
+        if !line.is_empty() && !line.starts_with('#') {
+            let mut path = PathBuf::new();
+            path.push(line);
+            list.paths.push(path);
+        }
+    }
+    Ok(list)
+}
 
-    // build the list of paths to match against.
-    let matchlist_paths: Vec<PathBuf> = matchlist_file
-        .lines()
-        .filter_map(|line| {
-            let line = line.unwrap();
-            if !line.is_empty() {
-                // skip empty lines
-                let mut path = PathBuf::new();
-                path.push(line);
-                Some(path)
-            } else {
-                None
+/// Read several matchlists into one, labelling each path with the
+/// matchlist it came from.
+fn load_matchlists(matchlists: &[&PathBuf]) -> Result<Matchlist, std::io::Error> {
+    let mut merged = Matchlist::default();
+    for matchlist in matchlists {
+        let Matchlist { paths, mut labels } = load_matchlist(matchlist)?;
+        for path in &paths {
+            labels.entry(path.clone()).or_default().db = Some(matchlist.display().to_string());
+        }
+        merged.paths.extend(paths);
+        merged.labels.extend(labels);
+    }
+    Ok(merged)
+}
+
+/// SBTs, LCA databases and zip collections are loaded whole, rather than
+/// listing signature files.
+fn is_database(path: &Path) -> bool {
+    sbt::is_sbt_zip(path) || lca::is_lca_db(path) || sigzip::is_collection(path)
+}
+
+/// Every signature in a database.
+fn load_database(path: &Path) -> Result<Vec<Signature>, Box<dyn std::error::Error>> {
+    let sigs = if sbt::is_sbt_zip(path) {
+        sbt::load_leaves(path)?
+    } else if lca::is_lca_db(path) {
+        lca::LcaDatabase::load(path)?.signatures()?
+    } else {
+        sigzip::load_all(path)?
+    };
+    info!("Loaded {} signatures from {}", sigs.len(), path.display());
+    Ok(sigs)
+}
+
+/// The sketch paths in a matchlist, without their labels.
+fn load_matchlist_paths<P: AsRef<Path>>(matchlist: P) -> Result<Vec<PathBuf>, std::io::Error> {
+    Ok(load_matchlist(matchlist)?.paths)
+}
+
+/// A query hash and the gather round that claimed it.
+#[derive(Serialize)]
+struct HashAssignment<'a> {
+    hashval: u64,
+    gather_rank: usize,
+    name: &'a str,
+    md5: &'a str,
+    query_md5: &'a str,
+}
+
+/// Save a single sketch as a signature file.
+fn write_minhash_sig<P: AsRef<Path>>(
+    path: P,
+    name: &str,
+    mh: &KmerMinHash,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let sig = Signature::builder()
+        .hash_function("0.murmur64")
+        .name(Some(name.to_string()))
+        .filename(None)
+        .signatures(vec![Sketch::MinHash(mh.clone())])
+        .build();
+    let mut writer = output::create(path)?;
+    serde_json::to_writer(&mut writer, &[sig])?;
+    writer.finish()?;
+    Ok(())
+}
+
+/// The part of `query` that `matched` claims, keeping query abundances.
+fn claimed_by(query: &KmerMinHash, matched: &KmerMinHash) -> Result<KmerMinHash, sourmash::Error> {
+    let common = intersect_hashes(query, matched);
+    let mut claimed = query.clone();
+    claimed.clear();
+    if query.track_abundance() {
+        let abunds: Vec<(u64, u64)> = query
+            .to_vec_abunds()
+            .into_iter()
+            .filter(|(hash, _)| common.binary_search(hash).is_ok())
+            .collect();
+        claimed.add_many_with_abund(&abunds)?;
+    } else {
+        claimed.add_many(&common)?;
+    }
+    Ok(claimed)
+}
+
+/// A matchlist sketch with some overlap with the original query.
+#[derive(Serialize, Default)]
+struct PrefetchRow<'a> {
+    name: &'a str,
+    md5: String,
+    /// filled in when the rows are written, with the scaled used and the
+    /// bp figure that follows from it
+    query_md5: &'a str,
+    scaled: u64,
+    intersect_hashes: u64,
+    intersect_bp: u64,
+    match_hashes: usize,
+    f_match: f64,
+    f_query: f64,
+    max_containment: f64,
+    jaccard: f64,
+}
+
+impl<'a> PrefetchRow<'a> {
+    fn new(
+        name: &'a str,
+        md5: &str,
+        containment: u64,
+        match_size: usize,
+        query_size: usize,
+    ) -> PrefetchRow<'a> {
+        PrefetchRow {
+            name,
+            md5: md5.to_string(),
+            query_md5: "",
+            scaled: 0,
+            intersect_hashes: containment,
+            intersect_bp: 0,
+            match_hashes: match_size,
+            f_match: containment as f64 / match_size as f64,
+            f_query: containment as f64 / query_size as f64,
+            max_containment: containment as f64 / match_size.min(query_size) as f64,
+            jaccard: jaccard(containment, match_size, query_size),
+        }
+    }
+
+    /// The row's score by `--metric`.
+    fn score(&self, metric: &str) -> f64 {
+        match metric {
+            "max-containment" => self.max_containment,
+            "jaccard" => self.jaccard,
+            _ => self.f_query,
+        }
+    }
+}
+
+/// Write the initial (pre-gather) containment of every candidate match,
+/// best by `metric` first; the candidates are all at the query's `scaled`.
+fn write_prefetch_csv<'a, P: AsRef<Path>>(
+    path: P,
+    candidates: &'a [Candidate],
+    query_size: usize,
+    query_md5: &'a str,
+    scaled: u64,
+    metric: &str,
+) -> Result<(), csv::Error> {
+    let mut rows: Vec<PrefetchRow> = candidates
+        .iter()
+        .map(|c| PrefetchRow::new(&c.name, &c.md5, c.containment, c.match_size, query_size))
+        .collect();
+    rows.sort_by(|a, b| {
+        b.score(metric)
+            .total_cmp(&a.score(metric))
+            .then_with(|| b.intersect_hashes.cmp(&a.intersect_hashes))
+            .then_with(|| a.name.cmp(b.name))
+    });
+
+    let mut writer = output::create_csv(path)?;
+    if rows.is_empty() {
+        output::write_header::<PrefetchRow, _>(&mut writer)?;
+    }
+    for mut row in rows {
+        row.query_md5 = query_md5;
+        row.scaled = scaled;
+        row.intersect_bp = row.intersect_hashes * scaled;
+        writer.serialize(row)?;
+    }
+    output::finish_csv(writer)?;
+    Ok(())
+}
+
+/// Load one matchlist entry, returning its first sketch compatible with the
+/// template that overlaps the query by at least `threshold_hashes`.
+///
+/// Files that can't be loaded are logged and recorded by the loader, which
+/// also reports the candidate found.
+fn load_candidate(
+    path: &Path,
+    template: &Sketch,
+    screen: &screen::QueryScreen,
+    threshold_hashes: u64,
+    auto_downsample: bool,
+    loader: &loader::Loader,
+) -> Option<Candidate> {
+    let sigs = loader.load(path)?;
+
+    let candidate = select_candidate(&sigs, template, screen, threshold_hashes, auto_downsample)?
+        .with_filename(path.display().to_string());
+    loader.found(&candidate);
+    Some(candidate)
+}
+
+/// The first of `sigs` compatible with the template that overlaps the
+/// query by at least `threshold_hashes`.
+fn select_candidate(
+    sigs: &[Signature],
+    template: &Sketch,
+    screen: &screen::QueryScreen,
+    threshold_hashes: u64,
+    auto_downsample: bool,
+) -> Option<Candidate> {
+    for sig in sigs {
+        let prepared = if auto_downsample {
+            prepare_query_any_scaled_with_source(sig, template)
+        } else {
+            prepare_query_with_source(sig, template)
+        };
+        let (mh, source) = match prepared {
+            Ok(prepared) => prepared,
+            Err(e) => {
+                debug!("{}: skipping {}: {}", sig.filename(), sig.name(), e);
+                continue;
             }
+        };
+        if let Some(containment) = screen.overlap(&mh, threshold_hashes) {
+            return Some(
+                Candidate::new(sig.name(), mh, containment)
+                    .with_filename(sig.filename())
+                    .with_source(source.as_ref()),
+            );
+        }
+    }
+    None
+}
+
+/// The signatures in a database that overlap the query by at least
+/// `threshold_hashes`, each a candidate of its own.
+fn database_candidates(
+    db: &Path,
+    template: &Sketch,
+    screen: &screen::QueryScreen,
+    threshold_hashes: u64,
+    auto_downsample: bool,
+) -> Result<Vec<Candidate>, Box<dyn std::error::Error>> {
+    let db_sigs = load_database(db)?;
+    let db_name = db.display().to_string();
+    Ok(db_sigs
+        .par_iter()
+        .filter_map(|sig| {
+            let result = select_candidate(
+                std::slice::from_ref(sig),
+                template,
+                screen,
+                threshold_hashes,
+                auto_downsample,
+            )?;
+            Some(Candidate {
+                db: Some(db_name.clone()),
+                ..result
+            })
         })
+        .collect())
+}
+
+/// The matchlist files that pass a prefetch with the query downsampled to
+/// `coarse_scaled`. The threshold is lowered by three standard deviations
+/// of the coarse overlap, so matches that pass at full resolution are
+/// rarely dropped.
+fn coarse_shortlist(
+    paths: &[PathBuf],
+    query: &KmerMinHash,
+    coarse_scaled: u64,
+    threshold_bp: u64,
+    loader: &loader::Loader,
+) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+    if coarse_scaled < query.scaled() {
+        return Err(format!(
+            "--coarse-scaled {} is finer than the query scaled {}",
+            coarse_scaled,
+            query.scaled()
+        )
+        .into());
+    }
+    let max_hash = max_hash_for_scaled(coarse_scaled);
+    let coarse_query = query.downsample_max_hash(max_hash)?;
+    let coarse_template = Sketch::MinHash(
+        KmerMinHash::builder()
+            .num(0u32)
+            .ksize(query.ksize() as u32)
+            .hash_function(query.hash_function())
+            .seed(query.seed())
+            .max_hash(max_hash)
+            .build(),
+    );
+
+    let expected = threshold_bp as f64 / coarse_scaled as f64;
+    let coarse_threshold = u64::max(1, (expected - 3.0 * expected.sqrt()).floor() as u64);
+    let screen = screen::QueryScreen::new(&coarse_query);
+    let shortlist: Vec<PathBuf> = paths
+        .par_iter()
+        .filter(|path| {
+            load_candidate(
+                path,
+                &coarse_template,
+                &screen,
+                coarse_threshold,
+                false,
+                loader,
+            )
+            .is_some()
+        })
+        .cloned()
         .collect();
+    info!(
+        "Shortlisted {} of {} matchlist files at scaled={}",
+        shortlist.len(),
+        paths.len(),
+        coarse_scaled
+    );
+    Ok(shortlist)
+}
 
-    // load the sketches in parallel; keep only those with some match.
-    let matchlist: BinaryHeap<PrefetchResult> = matchlist_paths
+/// Rough in-memory size of the sketches in a signature file, from its
+/// size on disk: JSON takes more bytes per hash than the parsed `u64`s.
+fn estimate_load_memory(path: &Path) -> u64 {
+    let size = std::fs::metadata(path).map(|m| m.len()).unwrap_or(0);
+    if path.extension().is_some_and(|ext| ext == "gz") {
+        size * 4
+    } else {
+        size
+    }
+}
+
+/// Bytes held by a trimmed candidate.
+fn estimate_candidate_memory(result: &Candidate) -> u64 {
+    let per_hash = if result.minhash.track_abundance() {
+        16
+    } else {
+        8
+    };
+    (result.minhash.size() * per_hash + result.name.len() + 128) as u64
+}
+
+/// Narrow the matchlist down with `--prefilter` and `--coarse-scaled`, if
+/// given, to the files that may overlap `query` by `threshold_hashes`.
+fn shortlist(
+    args: &GatherArgs,
+    mut paths: Vec<PathBuf>,
+    query: &KmerMinHash,
+    threshold_hashes: u64,
+    loader: &loader::Loader,
+) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+    let threshold_bp = threshold_hashes * query.scaled();
+    if let Some(index) = &args.prefilter {
+        let index = prefilter::PrefilterIndex::load(index)?;
+        paths = index.filter(paths, query, threshold_bp)?;
+    }
+    if let Some(coarse_scaled) = args.coarse_scaled {
+        paths = coarse_shortlist(&paths, query, coarse_scaled, threshold_bp, loader)?;
+    }
+    Ok(paths)
+}
+
+/// The candidates in the matchlist files `paths` and the `databases` that
+/// overlap the screened query by at least the threshold, loaded within
+/// `--max-memory` if given.
+fn load_candidates(
+    args: &GatherArgs,
+    paths: &[PathBuf],
+    labels: &HashMap<PathBuf, Label>,
+    databases: &[&PathBuf],
+    template: &Sketch,
+    screen: &screen::QueryScreen,
+    loader: &loader::Loader,
+) -> Result<BinaryHeap<Candidate>, Box<dyn std::error::Error>> {
+    let query = screen.query();
+    let threshold_hashes = threshold_hashes_for(args.threshold_bp, query.scaled());
+    let auto_downsample = args.auto_downsample;
+    let mut heap: BinaryHeap<Candidate> = match args.max_memory {
+        None => paths
+            .par_iter()
+            .filter_map(|m| {
+                load_candidate(
+                    m,
+                    template,
+                    screen,
+                    threshold_hashes,
+                    auto_downsample,
+                    loader,
+                )
+                .map(|result| result.labelled(labels.get(m)))
+            })
+            .collect(),
+        Some(max_memory) => load_candidates_chunked(
+            paths,
+            labels,
+            max_memory,
+            template,
+            query,
+            threshold_hashes,
+            loader,
+        )
+        .into(),
+    };
+    for db in databases {
+        heap.extend(database_candidates(
+            db,
+            template,
+            screen,
+            threshold_hashes,
+            auto_downsample,
+        )?);
+    }
+    Ok(heap)
+}
+
+/// Load the matchlist in chunks whose estimated size fits within
+/// `max_memory` (minus what the candidates kept so far use), trimming each
+/// chunk's candidates down to their overlap with the query.
+fn load_candidates_chunked(
+    paths: &[PathBuf],
+    labels: &HashMap<PathBuf, Label>,
+    max_memory: u64,
+    template: &Sketch,
+    query: &KmerMinHash,
+    threshold_hashes: u64,
+    loader: &loader::Loader,
+) -> Vec<Candidate> {
+    let screen = screen::QueryScreen::new(query);
+    let mut candidates: Vec<Candidate> = vec![];
+    let mut retained = 0u64;
+    let mut start = 0;
+    let mut n_chunks = 0;
+
+    while start < paths.len() {
+        let budget = max_memory.saturating_sub(retained);
+        let mut end = start;
+        let mut chunk_memory = 0;
+        // always take at least one file, so we make progress.
+        while end < paths.len() && (end == start || chunk_memory < budget) {
+            chunk_memory += estimate_load_memory(&paths[end]);
+            end += 1;
+        }
+
+        let chunk: Vec<Candidate> = paths[start..end]
+            .par_iter()
+            .filter_map(|m| {
+                let mut result =
+                    load_candidate(m, template, &screen, threshold_hashes, false, loader)?
+                        .labelled(labels.get(m));
+                result.trim_to(query);
+                Some(result)
+            })
+            .collect();
+
+        retained += chunk.iter().map(estimate_candidate_memory).sum::<u64>();
+        n_chunks += 1;
+        debug!(
+            "chunk {}: {} files, {} candidates; ~{} MiB retained",
+            n_chunks,
+            end - start,
+            chunk.len(),
+            retained >> 20
+        );
+        candidates.extend(chunk);
+        start = end;
+    }
+
+    info!(
+        "Loaded matchlist in {} chunks; kept {} candidates",
+        n_chunks,
+        candidates.len()
+    );
+    if retained > max_memory {
+        warn!(
+            "candidates use ~{} MiB, more than --max-memory",
+            retained >> 20
+        );
+    }
+    candidates
+}
+
+/// Parse a memory size like `32G`, `32GB`, `32GiB`, `512m` or `1000000`;
+/// suffixes are powers of 1024 and case doesn't matter.
+fn parse_memory(s: &str) -> Result<u64, String> {
+    let lower = s.trim().to_ascii_lowercase();
+    let unit = ["ib", "b"]
+        .iter()
+        .find_map(|suffix| lower.strip_suffix(suffix))
+        .unwrap_or(&lower);
+    let (number, multiplier) = match unit.chars().last() {
+        Some('k') => (&unit[..unit.len() - 1], 1u64 << 10),
+        Some('m') => (&unit[..unit.len() - 1], 1u64 << 20),
+        Some('g') => (&unit[..unit.len() - 1], 1u64 << 30),
+        Some('t') => (&unit[..unit.len() - 1], 1u64 << 40),
+        // `iB` only follows a unit.
+        _ if lower.ends_with("ib") => return Err(format!("invalid memory size: {}", s)),
+        _ => (unit, 1u64),
+    };
+    number
+        .parse::<f64>()
+        .map(|n| (n * multiplier as f64) as u64)
+        .map_err(|_| format!("invalid memory size: {}", s))
+}
+
+/// Parse a number that must be positive and finite, like a timeout.
+fn parse_positive(s: &str) -> Result<f64, String> {
+    match s.trim().parse::<f64>() {
+        Ok(n) if n > 0.0 && n.is_finite() => Ok(n),
+        Ok(_) => Err("must be a positive number".into()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Parse a fraction, between 0 and 1 inclusive, like an ANI threshold.
+fn parse_fraction(s: &str) -> Result<f64, String> {
+    match s.trim().parse::<f64>() {
+        Ok(n) if (0.0..=1.0).contains(&n) => Ok(n),
+        Ok(_) => Err("must be between 0 and 1".into()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// `--deterministic`: gather over `candidates`, as put in their stable
+/// order after loading, again on a single thread for `results.len()`
+/// rounds, and check that every round picked the same match with the same
+/// overlap.
+fn verify_deterministic(
+    mut query: KmerMinHash,
+    candidates: BinaryHeap<Candidate>,
+    args: &GatherArgs,
+    threshold_hashes: u64,
+    rank_by: RankBy,
+    results: &[GatherResult],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let pool = rayon::ThreadPoolBuilder::new().num_threads(1).build()?;
+    let picked = pool.install(|| -> Result<Vec<(String, u64)>, String> {
+        let mut matching_sketches: Box<dyn index::Index> =
+            Box::new(index::HeapIndex::new(candidates, args.speculate));
+        let mut picked = vec![];
+        while picked.len() < results.len() {
+            let best = match matching_sketches.best_match(&query, threshold_hashes, rank_by) {
+                Ok(Some(best)) => best,
+                Ok(None) => break,
+                Err(e) => return Err(e.to_string()),
+            };
+            if args.below_min_unique(best.containment * query.scaled()) {
+                continue;
+            }
+            if let Some(min_fraction) = args.prune_contained {
+                let claimed = claimed_by(&query, &best.minhash).map_err(|e| e.to_string())?;
+                matching_sketches.prune_contained(&claimed, min_fraction);
+            }
+            query
+                .remove_from(&best.minhash)
+                .map_err(|e| e.to_string())?;
+            matching_sketches.remove_hashes(&query, threshold_hashes);
+            picked.push((best.md5, best.containment));
+        }
+        Ok(picked)
+    })?;
+
+    for (result, (md5, overlap)) in results.iter().zip(&picked) {
+        if result.md5 != *md5 || result.unique_intersect_hashes != *overlap {
+            return Err(format!(
+                "--deterministic: round {} picked {} ({} hashes), but {} ({} hashes) on a single thread",
+                result.gather_rank, result.md5, result.unique_intersect_hashes, md5, overlap
+            )
+            .into());
+        }
+    }
+    if picked.len() != results.len() {
+        return Err(format!(
+            "--deterministic: {} rounds, but {} on a single thread",
+            results.len(),
+            picked.len()
+        )
+        .into());
+    }
+    info!(
+        "--deterministic: the {} rounds match a single-threaded run",
+        results.len()
+    );
+    Ok(())
+}
+
+/// Sort results for `--sort-by`: largest overlap or ANI first, or by
+/// name; ties stay in gather rank order. ANI grows with `f_match`, so
+/// that is what it is sorted on, whatever the ksize.
+fn sort_results(results: &mut [&GatherResult], sort_by: &str) {
+    match sort_by {
+        "overlap" => results.sort_by_key(|result| Reverse(result.intersect_bp)),
+        "ani" => results.sort_by(|a, b| b.f_match.total_cmp(&a.f_match)),
+        "name" => results.sort_by(|a, b| a.name.cmp(&b.name)),
+        _ => {}
+    }
+}
+
+/// `queries/x.sig.gz` -> `x`, naming the per-query outputs.
+fn query_basename(path: &Path) -> String {
+    let path = match path.extension() {
+        Some(ext) if ext == "gz" => path.with_extension(""),
+        _ => path.to_path_buf(),
+    };
+    path.file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+/// The output name of each `--query-list` query: its basename, or for
+/// queries sharing a basename, the basename and a short md5 of the path as
+/// listed, so that no two queries write to the same files.
+fn query_output_names(queries: &[PathBuf]) -> Vec<String> {
+    let basenames: Vec<String> = queries.iter().map(|query| query_basename(query)).collect();
+    let mut n_uses: HashMap<&str, usize> = HashMap::new();
+    for basename in &basenames {
+        *n_uses.entry(basename).or_default() += 1;
+    }
+    queries
+        .iter()
+        .zip(&basenames)
+        .map(|(query, basename)| {
+            if n_uses[basename.as_str()] == 1 {
+                return basename.clone();
+            }
+            let digest = format!(
+                "{:x}",
+                md5::compute(query.as_os_str().to_string_lossy().as_bytes())
+            );
+            format!("{}-{}", basename, &digest[..8])
+        })
+        .collect()
+}
+
+/// Run an independent gather for each query in the query list, writing
+/// one CSV per query to the output directory.
+fn gather_query_list(
+    args: &GatherArgs,
+    query_list: &Path,
+    failed_files: &Mutex<Vec<PathBuf>>,
+) -> Result<GatherStatus, Box<dyn std::error::Error>> {
+    let matchlist = args.matchlist.clone();
+    if matchlist.is_none() && args.db.is_empty() {
+        return Err("--query-list needs a matchlist or --db".into());
+    }
+    let output_dir = args.output_dir.as_ref().unwrap();
+    std::fs::create_dir_all(output_dir)?;
+
+    let queries = load_matchlist_paths(query_list)?;
+    let mut queries: Vec<(PathBuf, String)> = queries
+        .iter()
+        .cloned()
+        .zip(query_output_names(&queries))
+        .collect();
+    let output_for = |name: &str| output_dir.join(format!("{}.gather.csv", name));
+    // written once a gather is over, whether or not it found matches.
+    let marker_for = |name: &str| output_dir.join(format!("{}.done", name));
+    if args.skip_completed {
+        let n_queries = queries.len();
+        queries.retain(|(_, name)| !marker_for(name).exists());
+        info!(
+            "Skipping {} of {} queries already completed",
+            n_queries - queries.len(),
+            n_queries
+        );
+    }
+    // the signature file lists are read once; databases for every query.
+    let lists: Vec<&PathBuf> = matchlist
+        .iter()
+        .chain(&args.db)
+        .filter(|path| !is_database(path))
+        .collect();
+    let matchlist_paths = load_matchlists(&lists)?;
+    info!("Running {} gathers", queries.len());
+
+    let statuses: Vec<Result<GatherStatus, String>> = queries
         .par_iter()
-        .filter_map(|m| {
-            let sigs = Signature::from_path(m).unwrap();
-
-            let mut mm = None;
-            for sig in &sigs {
-                if let Some(mh) = prepare_query(sig, &template) {
-                    if let Ok(containment) = mh.count_common(&query, false) {
-                        if containment > 0 {
-                            let result = PrefetchResult {
-                                name: sig.name(),
-                                minhash: mh,
-                                containment,
-                            };
-                            mm = Some(result);
-                            break;
-                        }
-                    }
-                }
+        .map(|(query, name)| {
+            let query_args = GatherArgs {
+                query: Some(query.clone()),
+                matchlist: matchlist.clone(),
+                query_list: None,
+                output: Some(output_for(name)),
+                ..args.clone()
+            };
+            let status = do_countergather(
+                &query_args,
+                Some(&matchlist_paths),
+                None,
+                failed_files,
+                None,
+                &log_event,
+            )
+            .map_err(|e| format!("{}: {}", query.display(), e))?;
+            // a partial load is run again, in case the files are back.
+            if status != GatherStatus::PartialLoad {
+                File::create(marker_for(name))
+                    .map_err(|e| format!("{}: {}", query.display(), e))?;
+            }
+            Ok(status)
+        })
+        .collect();
+
+    combine_statuses(statuses, "queries")
+}
+
+/// The overall status of several independent gathers, logging those that
+/// failed; an error if any did.
+fn combine_statuses(
+    statuses: Vec<Result<GatherStatus, String>>,
+    what: &str,
+) -> Result<GatherStatus, Box<dyn std::error::Error>> {
+    let n_runs = statuses.len();
+    let mut n_errors = 0;
+    let mut status = GatherStatus::NoMatches;
+    for result in statuses {
+        match result {
+            Ok(GatherStatus::PartialLoad) => status = GatherStatus::PartialLoad,
+            Ok(GatherStatus::Matched) if status == GatherStatus::NoMatches => {
+                status = GatherStatus::Matched
             }
-            mm
+            Ok(_) => {}
+            Err(e) => {
+                error!("{}", e);
+                n_errors += 1;
+            }
+        }
+    }
+    if n_errors > 0 {
+        return Err(format!("{} of {} {} failed", n_errors, n_runs, what).into());
+    }
+    Ok(status)
+}
+
+/// `--per-contig`: sketch every record of the query on its own, gather
+/// each one independently against candidates loaded once for all of them,
+/// and write all the results to one CSV.
+fn gather_contigs(
+    args: &GatherArgs,
+    failed_files: &Mutex<Vec<PathBuf>>,
+) -> Result<GatherStatus, Box<dyn std::error::Error>> {
+    let query = args.query.as_ref().unwrap();
+    if !is_sequence_file(query) {
+        return Err("--per-contig needs a FASTA/FASTQ query".into());
+    }
+    let template = query_sketch_template(args)?;
+    info!("Sketching the records of {}", query.display());
+    let contigs = compute::sketch_records(query, &template, false)?;
+
+    // the candidates for any record are among those for the whole query.
+    let sigs = vec![compute::sketch_file(query, &template, false)?];
+    let gather_template = build_template(
+        &sigs,
+        args.template.ksize,
+        args.template.scaled,
+        args.template.moltype()?,
+        args.template.seed,
+    )?;
+    let (_, whole_query, _) = select_query(&sigs, &gather_template)?;
+    let threshold_hashes = threshold_hashes_for(args.threshold_bp, whole_query.scaled());
+
+    let (databases, lists): (Vec<&PathBuf>, Vec<&PathBuf>) = args
+        .matchlist
+        .iter()
+        .chain(&args.db)
+        .partition(|path| is_database(path));
+    let matchlist_paths = load_matchlists(&lists)?;
+    let cache_dir = args.cache_dir.as_deref();
+    if let Some(dir) = cache_dir {
+        std::fs::create_dir_all(dir)?;
+    }
+    info!("Loading matchlist");
+    let loader = loader::Loader::new(cache_dir, args.retry_policy(), failed_files);
+    let paths = shortlist(
+        args,
+        matchlist_paths.paths.clone(),
+        &whole_query,
+        threshold_hashes,
+        &loader,
+    )?;
+    let loader = loader.with_progress(paths.len(), &log_event);
+    let candidates = load_candidates(
+        args,
+        &paths,
+        &matchlist_paths.labels,
+        &databases,
+        &gather_template,
+        &screen::QueryScreen::new(&whole_query),
+        &loader,
+    )?
+    .into_vec();
+    let n_failed = loader.n_failed();
+    if n_failed > 0 {
+        warn!(
+            "{} of {} matchlist entries could not be loaded",
+            n_failed,
+            paths.len()
+        );
+    }
+    info!("Loaded {} candidates for all records", candidates.len());
+    info!("Running {} gathers", contigs.len());
+
+    let runs: Vec<(Result<GatherStatus, String>, Vec<GatherResult>)> = contigs
+        .into_par_iter()
+        .map(|contig| {
+            let name = contig.name();
+            let contig_args = GatherArgs {
+                output: None,
+                per_contig: false,
+                contig_query: Some(contig),
+                ..args.clone()
+            };
+            let results = Mutex::new(vec![]);
+            let status = do_countergather(
+                &contig_args,
+                Some(&matchlist_paths),
+                Some(&candidates),
+                failed_files,
+                Some(&results),
+                &log_event,
+            )
+            .map_err(|e| format!("{}: {}", name, e));
+            (status, results.into_inner().unwrap())
         })
         .collect();
 
-    if matchlist.is_empty() {
-        println!("No matchlist signatures loaded, exiting.");
-        return Ok(());
+    let output = args.output.as_ref().unwrap();
+    let mut writer = output::create_csv(output)?;
+    let mut n_results = 0;
+    let mut statuses = vec![];
+    for (status, results) in runs {
+        let mut sorted: Vec<&GatherResult> = results.iter().collect();
+        sort_results(&mut sorted, &args.sort_by);
+        for result in sorted {
+            writer.serialize(result)?;
+        }
+        n_results += results.len();
+        statuses.push(status);
+    }
+    output::finish_csv(writer)?;
+    info!(
+        "Wrote {} gather results for {} records to {}",
+        n_results,
+        statuses.len(),
+        output.display()
+    );
+    let status = combine_statuses(statuses, "records")?;
+    Ok(if n_failed > 0 {
+        GatherStatus::PartialLoad
+    } else {
+        status
+    })
+}
+
+/// Size the global rayon pool for `--threads`. It can only be set up
+/// once, so if it already is, e.g. by an earlier run in the same process,
+/// keep it and say so.
+fn use_threads(threads: Option<usize>) {
+    if let Some(threads) = threads {
+        if let Err(e) = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build_global()
+        {
+            warn!(
+                "--threads {}: {}; using the {} threads already set",
+                threads,
+                e,
+                rayon::current_num_threads()
+            );
+        }
+    }
+}
+
+/// Gather a single query, or every query in `--query-list`, within the
+/// requested number of threads.
+fn run_gather(args: &GatherArgs) -> Result<GatherStatus, Box<dyn std::error::Error>> {
+    let start = Instant::now();
+    use_threads(args.threads);
+    let failed_files = Mutex::new(vec![]);
+    let result = match &args.query_list {
+        Some(query_list) => gather_query_list(args, query_list, &failed_files),
+        None if args.per_contig => gather_contigs(args, &failed_files),
+        None => do_countergather(args, None, None, &failed_files, None, &log_event),
+    };
+
+    let metrics = metrics::RunMetrics::since(start);
+    metrics.log();
+    if let Some(path) = &args.summary_json {
+        let summary = metrics::RunSummary {
+            version: env!("CARGO_PKG_VERSION"),
+            query: args.query.as_deref(),
+            query_list: args.query_list.as_deref(),
+            matchlist: args.matchlist.as_deref(),
+            db: &args.db,
+            status: match &result {
+                Ok(status) => status.name(),
+                Err(_) => "error",
+            },
+            failed_files: {
+                let mut failed = failed_files.into_inner().unwrap();
+                failed.sort();
+                failed.dedup();
+                failed
+            },
+            metrics,
+        };
+        summary.write(path)?;
+        info!("Wrote run summary to {}", path.display());
+    }
+    result
+}
+
+/// Log what a gather reports while it runs.
+fn log_event(event: GatherEvent) {
+    match event {
+        GatherEvent::LoadProgress { loaded, total } => {
+            debug!("loaded {} of {} matchlist files", loaded, total)
+        }
+        GatherEvent::CandidateFound(candidate) => trace!("candidate {}", candidate.name),
+        GatherEvent::RoundComplete {
+            result,
+            remaining_hashes,
+            candidates,
+        } => trace!(
+            "round {} picked {}; {} hashes and {} candidates left",
+            result.gather_rank,
+            result.name,
+            remaining_hashes,
+            candidates
+        ),
+    }
+}
+
+/// Stop any loading `matching_sketches` still does, and record the files
+/// that failed to load after gather began, e.g. in the background.
+fn take_late_failures(
+    matching_sketches: &mut dyn index::Index,
+    failed_files: &Mutex<Vec<PathBuf>>,
+    status: &mut GatherStatus,
+) {
+    let late_failures = matching_sketches.stop_loading();
+    if !late_failures.is_empty() {
+        warn!(
+            "{} matchlist entries could not be loaded during gather",
+            late_failures.len()
+        );
+        failed_files.lock().unwrap().extend(late_failures);
+        *status = GatherStatus::PartialLoad;
+    }
+}
+
+/// Gather one query; `shared_matchlist` is the already-read signature
+/// file lists when several queries are searched against them, and
+/// `shared_candidates` the candidates already loaded for all of them, if
+/// they were. Matchlist files that couldn't be loaded are added to
+/// `failed_files`, and the results to `collected` if given. Progress goes
+/// to `on_event`, except for matchlist files loaded in the background with
+/// `--early-start-bp`.
+fn do_countergather(
+    args: &GatherArgs,
+    shared_matchlist: Option<&Matchlist>,
+    shared_candidates: Option<&[Candidate]>,
+    failed_files: &Mutex<Vec<PathBuf>>,
+    collected: Option<&Mutex<Vec<GatherResult>>>,
+    on_event: &(dyn Fn(GatherEvent) + Sync),
+) -> Result<GatherStatus, Box<dyn std::error::Error>> {
+    let query_filename = args.query.as_ref().unwrap();
+    let auto_downsample = args.auto_downsample;
+
+    info!("Loading query");
+    debug!("query filename: {}", query_filename.display());
+    let sigs = load_query_sigs(query_filename, args)?;
+
+    let template = build_template(
+        &sigs,
+        args.template.ksize,
+        args.template.scaled,
+        args.template.moltype()?,
+        args.template.seed,
+    )?;
+    if let Sketch::MinHash(template_mh) = &template {
+        info!(
+            "Using template k={} scaled={} moltype={}",
+            ksize_of(template_mh),
+            template_mh.scaled(),
+            template_mh.hash_function()
+        );
+    }
+
+    let (query_name, mut query, query_source) = select_query(&sigs, &template)?;
+    let loaded_query_md5 = query_source.as_ref().unwrap_or(&query).md5sum();
+
+    let abundances_ignored = args.ignore_abundance && query.track_abundance();
+    if abundances_ignored {
+        info!("Ignoring query abundances");
+        query.disable_abundance();
     }
 
-    let mut matching_sketches = matchlist;
+    let mut threshold_hashes = threshold_hashes_for(args.threshold_bp, query.scaled());
+
+    if shared_candidates.is_none() {
+        info!("Loading matchlist");
+    }
+    let (databases, lists): (Vec<&PathBuf>, Vec<&PathBuf>) = args
+        .matchlist
+        .iter()
+        .chain(&args.db)
+        .partition(|path| is_database(path));
+    if !databases.is_empty()
+        && (args.lazy_load
+            || args.no_prefetch
+            || args.max_memory.is_some()
+            || args.prefilter.is_some()
+            || args.coarse_scaled.is_some())
+    {
+        return Err(concat!(
+            "--lazy-load, --no-prefetch, --max-memory, --prefilter and ",
+            "--coarse-scaled need a list of signature files, not a database"
+        )
+        .into());
+    }
+    let Matchlist {
+        paths: mut matchlist_paths,
+        labels,
+    } = match shared_matchlist {
+        Some(list) => list.clone(),
+        None => load_matchlists(&lists)?,
+    };
+
+    let cache_dir = args.cache_dir.as_deref();
+    if let Some(dir) = cache_dir {
+        std::fs::create_dir_all(dir)?;
+    }
+
+    // load the sketches in parallel; keep only those with some match.
+    let loader = loader::Loader::new(cache_dir, args.retry_policy(), failed_files);
+    if shared_candidates.is_none() {
+        matchlist_paths = shortlist(args, matchlist_paths, &query, threshold_hashes, &loader)?;
+    }
+    let loader = loader.with_progress(matchlist_paths.len(), on_event);
+    let screen = screen::QueryScreen::new(&query);
+    // a copy of the candidates for --deterministic and --explain-match to
+    // gather over again.
+    let mut replay_candidates = None;
+    // how many candidates each representative stands for.
+    let mut derep_members: HashMap<String, usize> = HashMap::new();
+    let mut matching_sketches: Box<dyn index::Index> = if args.lazy_load {
+        let candidates: Vec<lazy::LazyCandidate> = matchlist_paths
+            .par_iter()
+            .filter_map(|m| {
+                let result =
+                    load_candidate(m, &template, &screen, threshold_hashes, false, &loader)?
+                        .labelled(labels.get(m));
+                Some(lazy::LazyCandidate::new(m.clone(), result))
+            })
+            .collect();
+        Box::new(lazy::LazyMatches::new(
+            candidates,
+            &template,
+            args.lazy_cache_size,
+            cache_dir,
+            args.retry_policy(),
+        ))
+    } else if args.no_prefetch {
+        let paths: Vec<PathBuf> = matchlist_paths
+            .par_iter()
+            .filter(|m| {
+                load_candidate(m, &template, &screen, threshold_hashes, false, &loader).is_some()
+            })
+            .cloned()
+            .collect();
+        Box::new(stream::StreamingMatches::new(
+            paths,
+            labels,
+            &template,
+            cache_dir,
+            args.retry_policy(),
+        ))
+    } else if let Some(early_start_bp) = args.early_start_bp {
+        let mut heap = BinaryHeap::new();
+        for db in &databases {
+            heap.extend(database_candidates(
+                db,
+                &template,
+                &screen,
+                threshold_hashes,
+                false,
+            )?);
+        }
+        let job = background::LoadJob {
+            paths: matchlist_paths.clone(),
+            labels,
+            template: template.clone(),
+            query: query.clone(),
+            threshold_hashes,
+            cache_dir: cache_dir.map(Path::to_path_buf),
+            retry: args.retry_policy(),
+            load_rate: args.load_rate,
+            threads: rayon::current_num_threads(),
+        };
+        info!("Loading the matchlist in the background");
+        Box::new(background::BackgroundIndex::start(
+            job,
+            heap,
+            early_start_bp / query.scaled(),
+            args.speculate,
+        ))
+    } else {
+        let mut heap: BinaryHeap<Candidate> = match shared_candidates {
+            Some(candidates) => candidates
+                .par_iter()
+                .filter_map(|candidate| {
+                    let containment = candidate
+                        .minhash
+                        .count_common(&query, auto_downsample)
+                        .ok()?;
+                    (containment >= threshold_hashes).then(|| Candidate {
+                        containment,
+                        ..candidate.clone()
+                    })
+                })
+                .collect(),
+            None => load_candidates(
+                args,
+                &matchlist_paths,
+                &labels,
+                &databases,
+                &template,
+                &screen,
+                &loader,
+            )?,
+        };
+
+        // bring the query and all matches down to the coarsest scaled seen.
+        if auto_downsample {
+            let max_hash = heap
+                .iter()
+                .map(|r| r.minhash.max_hash())
+                .fold(query.max_hash(), u64::min);
+
+            if max_hash < query.max_hash() {
+                info!(
+                    "Downsampling query and matches to scaled={}",
+                    scaled_for_max_hash(max_hash)
+                );
+                query = query.downsample_max_hash(max_hash)?;
+                threshold_hashes = threshold_hashes_for(args.threshold_bp, query.scaled());
+                let downsampled: BinaryHeap<Candidate> = std::mem::take(&mut heap)
+                    .into_par_iter()
+                    .map(|result| {
+                        if result.minhash.max_hash() == max_hash {
+                            return result;
+                        }
+                        // still reported under the md5 it was loaded with.
+                        let minhash = result.minhash.downsample_max_hash(max_hash).unwrap();
+                        Candidate {
+                            match_size: minhash.size(),
+                            minhash,
+                            ..result
+                        }
+                    })
+                    .collect();
+                heap = prefetch(&query, downsampled, threshold_hashes);
+            }
+        }
+        // candidates that compare equal, like one sketch in two files, may
+        // be collected in any order; put them in a stable one, so that ties
+        // are picked the same however the threads were scheduled.
+        let mut collected = heap.into_vec();
+        collected.sort_by(Candidate::cmp_stable);
+        heap = BinaryHeap::from(collected);
+        if let Some(min_ani) = args.derep_threshold {
+            let (representatives, rows) = derep::dereplicate(heap, min_ani);
+            heap = representatives;
+            for row in &rows {
+                *derep_members
+                    .entry(row.representative_md5.clone())
+                    .or_default() += 1;
+            }
+            if let Some(path) = &args.output_derep {
+                derep::write_derep_csv(path, &rows)?;
+                info!(
+                    "Wrote dereplication of {} candidates to {}",
+                    rows.len(),
+                    path.display()
+                );
+            }
+        }
+        if args.deterministic || args.explain_match.is_some() {
+            replay_candidates = Some(heap.clone());
+        }
+        Box::new(index::HeapIndex::new(heap, args.speculate))
+    };
+
+    let n_failed = loader.n_failed();
+    if n_failed > 0 {
+        warn!(
+            "{} of {} matchlist entries could not be loaded",
+            n_failed,
+            matchlist_paths.len()
+        );
+    }
+    let mut loaded_status = if n_failed > 0 {
+        GatherStatus::PartialLoad
+    } else {
+        GatherStatus::Matched
+    };
+
+    // the query as used, which auto-downsampling may have changed.
+    let query_md5 = query.md5sum();
+    let query_source_md5 = (query_md5 != loaded_query_md5).then_some(loaded_query_md5);
+
+    // written even without candidates, as a header-only CSV.
+    if let Some(path) = &args.output_prefetch {
+        write_prefetch_csv(
+            path,
+            &matching_sketches.prefetch(),
+            query.size(),
+            &query_md5,
+            query.scaled(),
+            &args.metric,
+        )?;
+        info!(
+            "Wrote {} prefetch matches to {}",
+            matching_sketches.len(),
+            path.display()
+        );
+    }
+    if matching_sketches.is_empty() {
+        info!("No matchlist signatures loaded, exiting.");
+        take_late_failures(matching_sketches.as_mut(), failed_files, &mut loaded_status);
+        return Ok(loaded_status.or_no_matches());
+    }
+    if args.no_gather {
+        take_late_failures(matching_sketches.as_mut(), failed_files, &mut loaded_status);
+        return Ok(loaded_status);
+    }
+
+    let query_size = query.size();
+    let orig_query = query.clone();
+    let mut results: Vec<GatherResult> = vec![];
+
+    // rows sorted otherwise can only be written once all are known.
+    let sorted_by_rank = args.sort_by == "rank";
+    // rows written as they are found go straight to the output, so that it
+    // can be followed while gather runs.
+    let mut gather_writer = match &args.output {
+        Some(path) if sorted_by_rank => Some(output::create_csv_in_place(path)?),
+        Some(path) => Some(output::create_csv(path)?),
+        None => None,
+    };
+    let compat_info = args.compat.as_ref().map(|_| compat::QueryInfo {
+        filename: query_filename.display().to_string(),
+        name: query_name.clone(),
+        abundance: query.track_abundance(),
+    });
+    let mut compat_weighted_found = 0;
+
+    // the matches in result order, kept for --output-redundancy.
+    let mut selected: Vec<Candidate> = vec![];
+
+    let total_weighted_hashes = query.abunds().map(|abunds| abunds.iter().sum::<u64>());
+    let mut sum_weighted_found = 0;
+
+    let mut hashes_writer = match &args.output_hashes {
+        Some(path) => Some(output::create_csv(path)?),
+        None => None,
+    };
+
+    let mut round_diffs = match &args.save_round_diffs {
+        Some(path) => Some(sigzip::ZipCollectionWriter::create(path)?),
+        None => None,
+    };
+
+    let mut report = if args.report {
+        Some(report::Report::new(args.color))
+    } else {
+        None
+    };
 
     // loop until no more matching sketches -
-    while !matching_sketches.is_empty() {
-        println!("remaining: {} {}", query.size(), matching_sketches.len());
-        let best_element = matching_sketches.peek().unwrap();
+    let rank_by = RankBy::from_name(&args.rank_by);
+    if rank_by == RankBy::Weighted && !query.track_abundance() {
+        return Err("--rank-by weighted needs a query with abundances".into());
+    }
+    if rank_by != RankBy::Overlap && args.lazy_load {
+        return Err("--lazy-load only supports --rank-by overlap".into());
+    }
+    if rank_by != RankBy::Overlap && args.early_start_bp.is_some() {
+        return Err("--early-start-bp only supports --rank-by overlap".into());
+    }
+
+    let mut n_pruned = 0;
+
+    if args.fair_split.is_some() && rank_by != RankBy::Overlap {
+        return Err("--fair-split only supports --rank-by overlap".into());
+    }
+    // with --fair-split, the members of the current co-equal group still to
+    // be removed, with their shares of the hashes the group claimed.
+    let mut coequal: VecDeque<(Candidate, f64)> = VecDeque::new();
+    let mut split_group = 0;
+    let mut split_total = 0.0;
+    // the query size before the group, and the shares of its members so far
+    let mut split_query_size = 0;
+    let mut split_assigned = 0.0;
+    // matches left out by --min-unique-bp
+    let mut n_dropped = 0;
+
+    loop {
+        let (best_element, split_share) = match coequal.pop_front() {
+            Some((mut member, share)) => {
+                member.containment = member.minhash.count_common(&query, false)?;
+                (member, Some(share))
+            }
+            None => {
+                let best = match matching_sketches.best_match(&query, threshold_hashes, rank_by)? {
+                    Some(best) => best,
+                    None => break,
+                };
+                let rest = match args.fair_split {
+                    Some(z) => matching_sketches.pop_coequal(&best, z),
+                    None => vec![],
+                };
+                let group: Vec<Candidate> = std::iter::once(best).chain(rest).collect();
+                let (group, left_out) = split::split_group(&query, group, threshold_hashes);
+                matching_sketches.restore(left_out);
+                if group.len() == 1 {
+                    (group.into_iter().next().unwrap().0, None)
+                } else {
+                    split_group = results.len();
+                    split_total = group.iter().map(|(_, share)| share).sum();
+                    split_query_size = query.size();
+                    split_assigned = 0.0;
+                    info!(
+                        "splitting {:.0} hashes between {} co-equal matches",
+                        split_total,
+                        group.len()
+                    );
+                    let mut members = group.into_iter();
+                    let (best, share) = members.next().unwrap();
+                    coequal.extend(members);
+                    (best, Some(share))
+                }
+            }
+        };
+        debug!("remaining: {} {}", query.size(), matching_sketches.len());
+
+        let mut result = GatherResult::new(
+            results.len(),
+            &best_element,
+            best_element.containment,
+            query.size(),
+            &orig_query,
+            &query_md5,
+        )?;
+        if let Some(share) = split_share {
+            result.split(
+                share,
+                split_query_size as f64 - (split_assigned + share),
+                orig_query.size(),
+            );
+            result.split_group = Some(split_group);
+            result.split_fraction = Some(share / split_total);
+        }
+        // left out before anything is written, and its hashes stay in the
+        // query; by overlap, every later match has even fewer unique hashes.
+        if args.below_min_unique(result.unique_intersect_bp) {
+            debug!("leaving out {}: too few unique bp", best_element.name);
+            n_dropped += 1;
+            if rank_by == RankBy::Overlap && coequal.is_empty() {
+                break;
+            }
+            continue;
+        }
+        if let Some(share) = split_share {
+            split_assigned += share;
+        }
+        result.query_source_md5 = query_source_md5.clone();
+        if let Some(total) = total_weighted_hashes {
+            sum_weighted_found += weighted_overlap(&query, &best_element.minhash);
+            result.sum_weighted_found = Some(sum_weighted_found);
+            result.total_weighted_hashes = Some(total);
+            let depth = weighted_overlap(&orig_query, &best_element.minhash);
+            result.coverage = Some(depth as f64 / best_element.match_size as f64);
+        }
+        if abundances_ignored {
+            result.query_abundance = "ignored";
+        }
+        result.derep_members = derep_members.get(&result.md5).copied();
+        if args.contig_query.is_some() {
+            result.contig = Some(query_name.clone());
+        }
+        result.name = args.format_name(&result.name);
+        results.push(result);
+
+        // written as soon as it's found, so partial runs leave results.
+        let compat_row = compat_info.as_ref().map(|info| {
+            compat::SourmashRow::new(
+                results.last().unwrap(),
+                &best_element,
+                &query,
+                &orig_query,
+                info,
+                compat_weighted_found,
+            )
+        });
+        if let Some(row) = &compat_row {
+            compat_weighted_found = row.sum_weighted_found;
+        }
+        if let (Some(writer), true) = (gather_writer.as_mut(), sorted_by_rank) {
+            match &compat_row {
+                Some(row) => writer.serialize(row)?,
+                None => writer.serialize(results.last().unwrap())?,
+            }
+            writer.flush()?;
+        }
+        if let Some(report) = report.as_mut() {
+            let result = results.last().unwrap();
+            let match_size = best_element.match_size as f64;
+            let orig_overlap = best_element.minhash.count_common(&orig_query, false)?;
+            let containment = orig_overlap as f64 / match_size;
+            let ksize = ksize_of(&best_element.minhash) as f64;
+            report.row(&report::ReportRow {
+                overlap_bp: result.intersect_bp,
+                p_query: result.f_unique_to_query,
+                p_match: result.unique_intersect_hashes as f64 / match_size,
+                ani: containment.powf(1.0 / ksize),
+                name: &result.name,
+            });
+        }
+
+        if let Some(writer) = hashes_writer.as_mut() {
+            let rank = results.len() - 1;
+            let md5 = &results[rank].md5;
+            for hashval in intersect_hashes(&query, &best_element.minhash) {
+                writer.serialize(HashAssignment {
+                    hashval,
+                    gather_rank: rank,
+                    name: &best_element.name,
+                    md5,
+                    query_md5: &query_md5,
+                })?;
+            }
+        }
+
+        if let Some(writer) = round_diffs.as_mut() {
+            let claimed = claimed_by(&query, &best_element.minhash)?;
+            writer.add(&results.last().unwrap().name, &claimed)?;
+        }
+
+        if let Some(min_fraction) = args.prune_contained {
+            let claimed = claimed_by(&query, &best_element.minhash)?;
+            let n = matching_sketches.prune_contained(&claimed, min_fraction);
+            debug!("pruned {} candidates contained in {}", n, best_element.name);
+            n_pruned += n;
+        }
 
         // remove!
-        println!("removing {}", best_element.name);
+        info!(
+            "removing {} ({:.1}% of query assigned)",
+            best_element.name,
+            results.last().unwrap().f_found_cumulative * 100.0
+        );
         query.remove_from(&best_element.minhash)?;
+        on_event(GatherEvent::RoundComplete {
+            result: results.last().unwrap(),
+            remaining_hashes: query.size(),
+            candidates: matching_sketches.len(),
+        });
+        if args.output_redundancy.is_some() {
+            selected.push(best_element);
+        }
+
+        if let (Some(every), Some(prefix)) = (args.save_query_every, &args.save_query_prefix) {
+            let round = results.len();
+            if every > 0 && round.is_multiple_of(every) {
+                let path =
+                    args.signature_path(Path::new(&format!("{}.{}.sig", prefix.display(), round)));
+                write_minhash_sig(&path, &format!("{} round {}", query_name, round), &query)?;
+                info!("Saved remaining query to {}", path.display());
+            }
+        }
+
+        if let Some(stop_at) = args.stop_at_fraction {
+            let last = results.last().unwrap();
+            let found = match (last.sum_weighted_found, last.total_weighted_hashes) {
+                (Some(found), Some(total)) => found as f64 / total as f64,
+                _ => last.f_found_cumulative,
+            };
+            if found >= stop_at {
+                info!(
+                    "Stopping after {:.1}% of the query was assigned",
+                    found * 100.0
+                );
+                break;
+            }
+        }
 
         // recalculate remaining containments between query and all sketches.
-        matching_sketches = prefetch(&query, matching_sketches);
+        matching_sketches.remove_hashes(&query, threshold_hashes);
     }
 
-    Ok(())
+    take_late_failures(matching_sketches.as_mut(), failed_files, &mut loaded_status);
+    matching_sketches.finish();
+    if args.prune_contained.is_some() {
+        info!(
+            "Pruned {} candidates contained in selected matches",
+            n_pruned
+        );
+    }
+
+    if let (Some(candidates), Some(selector)) = (&replay_candidates, &args.explain_match) {
+        explain::explain_match(
+            &orig_query,
+            candidates.clone(),
+            selector,
+            args,
+            threshold_hashes,
+            rank_by,
+            results.len(),
+        )?;
+    }
+    if let (Some(candidates), true) = (replay_candidates, args.deterministic) {
+        verify_deterministic(
+            orig_query.clone(),
+            candidates,
+            args,
+            threshold_hashes,
+            rank_by,
+            &results,
+        )?;
+    }
+
+    if let Some(min_unique_bp) = args.min_unique_bp {
+        info!(
+            "Left out {} matches with less than {} bp unique to them",
+            n_dropped, min_unique_bp
+        );
+    }
+
+    if let Some(report) = &report {
+        report.finish(1.0 - query.size() as f64 / query_size as f64);
+    }
+
+    if let Some(path) = &args.output_unassigned {
+        let path = &args.signature_path(path);
+        write_minhash_sig(path, &format!("{} unassigned", query_name), &query)?;
+        info!(
+            "Wrote {} unassigned hashes to {}",
+            query.size(),
+            path.display()
+        );
+    }
+
+    if let Some(path) = &args.output_found {
+        let path = &args.signature_path(path);
+        let mut found = orig_query.clone();
+        found.remove_from(&query)?;
+        write_minhash_sig(path, &format!("{} found", query_name), &found)?;
+        info!("Wrote {} found hashes to {}", found.size(), path.display());
+    }
+
+    if let Some(writer) = hashes_writer {
+        output::finish_csv(writer)?;
+        info!(
+            "Wrote hash assignments to {}",
+            args.output_hashes.as_ref().unwrap().display()
+        );
+    }
+
+    if let Some(writer) = round_diffs {
+        writer.finish()?;
+        info!(
+            "Wrote the hashes removed in each round to {}",
+            args.save_round_diffs.as_ref().unwrap().display()
+        );
+    }
+
+    if let Some(path) = &args.output_redundancy {
+        redundancy::write_redundancy_csv(path, &results, &selected, &orig_query)?;
+        info!("Wrote overlaps between matches to {}", path.display());
+    }
+
+    if let Some(output) = &args.output {
+        let mut writer = gather_writer.unwrap();
+        if !sorted_by_rank {
+            let mut sorted: Vec<&GatherResult> = results.iter().collect();
+            sort_results(&mut sorted, &args.sort_by);
+            for result in sorted {
+                writer.serialize(result)?;
+            }
+        }
+        output::finish_csv(writer)?;
+        info!(
+            "Wrote {} gather results to {}",
+            results.len(),
+            output.display()
+        );
+
+        if let Some(lineages) = &args.taxonomy {
+            let taxonomy = if lca::is_lca_db(lineages) {
+                taxonomy::Taxonomy::from_lca(&lca::LcaDatabase::load(lineages)?)
+            } else {
+                taxonomy::Taxonomy::from_csv(lineages)?
+            };
+            let summary_path = taxonomy::summary_path(output);
+            taxonomy.write_summary(&summary_path, &query_name, &results)?;
+            info!("Wrote taxonomy summary to {}", summary_path.display());
+        }
+    }
+
+    let status = if results.is_empty() {
+        loaded_status.or_no_matches()
+    } else {
+        loaded_status
+    };
+    if let Some(collected) = collected {
+        collected.lock().unwrap().extend(results);
+    }
+    Ok(status)
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let opts = Cli::parse();
+/// Send diagnostics to stderr at the requested verbosity; `RUST_LOG`
+/// overrides the command line.
+fn init_logging(verbose: u8, quiet: bool) {
+    let level = match (quiet, verbose) {
+        (true, _) => log::LevelFilter::Error,
+        (false, 0) => log::LevelFilter::Info,
+        (false, 1) => log::LevelFilter::Debug,
+        (false, _) => log::LevelFilter::Trace,
+    };
+    env_logger::Builder::new()
+        .filter_level(level)
+        .format_timestamp(None)
+        .target(env_logger::Target::Stderr)
+        .parse_default_env()
+        .init();
+}
 
-    do_countergather(opts.query, opts.matchlist)
+fn run() -> Result<GatherStatus, Box<dyn std::error::Error>> {
+    let matches = Cli::command().get_matches();
+    let mut opts = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    init_logging(opts.verbose, opts.quiet);
+
+    let config = config::Config::load(opts.config.as_deref())?;
+    config.apply(&mut opts.gather, &matches);
+    opts.gather.resolve_positionals()?;
+
+    if let Some(command) = opts.command {
+        match command {
+            Command::Describe { path } => describe::describe(path)?,
+            Command::Check(mut args) => {
+                if let Some(matches) = matches.subcommand_matches("check") {
+                    config.apply(&mut args, matches);
+                }
+                args.resolve_positionals()?;
+                check::check(&args)?
+            }
+            Command::Search(mut args) => {
+                if let Some(matches) = matches.subcommand_matches("search") {
+                    search::reject_ignored(matches)?;
+                    config.apply(&mut args.gather, matches);
+                }
+                args.gather.resolve_positionals()?;
+                return search::search(&args);
+            }
+            Command::Sketch(args) => compute::sketch(&args)?,
+            Command::Intersect(args) => sigops::run(sigops::SigOp::Intersect, &args)?,
+            Command::Subtract(args) => sigops::run(sigops::SigOp::Subtract, &args)?,
+            Command::Merge(args) => sigops::run(sigops::SigOp::Merge, &args)?,
+            Command::Index(args) => prefilter::index(&args)?,
+            Command::Bench(args) => bench::bench(&args)?,
+            Command::Matrix(args) => matrix::matrix(&args)?,
+            Command::Cluster(args) => cluster::cluster(&args)?,
+            Command::Serve(args) => serve::serve(&args)?,
+            #[cfg(feature = "grpc")]
+            Command::ServeGrpc(args) => grpc::serve_grpc(&args)?,
+        };
+        return Ok(GatherStatus::Matched);
+    }
+
+    run_gather(&opts.gather)
+}
+
+fn main() {
+    let code = match run() {
+        Ok(status) => status.exit_code(),
+        Err(e) => {
+            error!("{}", e);
+            if e.is::<NoCompatibleQuery>() {
+                EXIT_NO_COMPATIBLE_QUERY
+            } else {
+                EXIT_ERROR
+            }
+        }
+    };
+    std::process::exit(code);
 }
@@ -0,0 +1,85 @@
+//! All-vs-all comparison of a collection of signatures.
+
+use std::path::PathBuf;
+
+use clap::Args;
+use log::info;
+use rayon::prelude::*;
+
+use crate::collection::CollectionArgs;
+use crate::{output, Candidate};
+
+#[derive(Args, Debug)]
+pub struct MatrixArgs {
+    #[clap(flatten)]
+    sigs: CollectionArgs,
+
+    /// write the labelled matrix to this CSV file
+    #[clap(short = 'o', long = "output", parse(from_os_str))]
+    output: PathBuf,
+
+    /// jaccard; containment of the row in the column; or containment of
+    /// the smaller sketch in the larger one, which is symmetric
+    #[clap(
+        long = "metric",
+        default_value = "jaccard",
+        possible_values = &["jaccard", "containment", "max-containment"]
+    )]
+    metric: String,
+}
+
+pub fn matrix(args: &MatrixArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let sketches: Vec<Candidate> = args.sigs.load()?.into_iter().map(|(_, s)| s).collect();
+    let n = sketches.len();
+
+    // only the upper triangle is computed; the overlap is symmetric.
+    let upper: Vec<Vec<u64>> = (0..n)
+        .into_par_iter()
+        .map(|i| {
+            ((i + 1)..n)
+                .map(|j| {
+                    sketches[i]
+                        .minhash
+                        .count_common(&sketches[j].minhash, false)
+                        .unwrap()
+                })
+                .collect()
+        })
+        .collect();
+    let common = |i: usize, j: usize| match i.cmp(&j) {
+        std::cmp::Ordering::Less => upper[i][j - i - 1],
+        std::cmp::Ordering::Greater => upper[j][i - j - 1],
+        std::cmp::Ordering::Equal => sketches[i].match_size as u64,
+    };
+
+    let mut writer = output::create_csv(&args.output)?;
+    let mut header = vec!["name"];
+    header.extend(sketches.iter().map(|s| s.name.as_str()));
+    writer.write_record(&header)?;
+
+    for i in 0..n {
+        let mut record = vec![sketches[i].name.clone()];
+        for j in 0..n {
+            let overlap = common(i, j) as f64;
+            let size_i = sketches[i].match_size as f64;
+            let size_j = sketches[j].match_size as f64;
+            let value = match args.metric.as_str() {
+                "containment" => overlap / size_i,
+                "max-containment" => overlap / size_i.min(size_j),
+                _ => overlap / (size_i + size_j - overlap),
+            };
+            record.push((if value.is_nan() { 0.0 } else { value }).to_string());
+        }
+        writer.write_record(&record)?;
+    }
+    output::finish_csv(writer)?;
+
+    info!(
+        "Wrote {}x{} {} matrix to {}",
+        n,
+        n,
+        args.metric,
+        args.output.display()
+    );
+    Ok(())
+}
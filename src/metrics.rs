@@ -0,0 +1,96 @@
+//! Resource use of the run: wall-clock time, CPU time and peak memory.
+
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use log::info;
+use serde::Serialize;
+
+use crate::output;
+
+#[derive(Serialize, Debug)]
+pub(crate) struct RunMetrics {
+    wall_time_s: f64,
+    /// user plus system time of all threads; not available everywhere
+    cpu_time_s: Option<f64>,
+    peak_rss_bytes: Option<u64>,
+}
+
+#[cfg(unix)]
+fn rusage() -> Option<(f64, u64)> {
+    let mut usage = std::mem::MaybeUninit::<libc::rusage>::uninit();
+    // SAFETY: getrusage fills in the struct it is given.
+    let usage = unsafe {
+        if libc::getrusage(libc::RUSAGE_SELF, usage.as_mut_ptr()) != 0 {
+            return None;
+        }
+        usage.assume_init()
+    };
+    let seconds = |t: libc::timeval| t.tv_sec as f64 + t.tv_usec as f64 / 1e6;
+    let cpu = seconds(usage.ru_utime) + seconds(usage.ru_stime);
+    // ru_maxrss is in bytes on macOS and kilobytes elsewhere.
+    let maxrss = usage.ru_maxrss as u64;
+    let peak_rss = if cfg!(target_os = "macos") {
+        maxrss
+    } else {
+        maxrss * 1024
+    };
+    Some((cpu, peak_rss))
+}
+
+#[cfg(not(unix))]
+fn rusage() -> Option<(f64, u64)> {
+    None
+}
+
+impl RunMetrics {
+    /// Resource use from `start` until now.
+    pub(crate) fn since(start: Instant) -> RunMetrics {
+        let usage = rusage();
+        RunMetrics {
+            wall_time_s: start.elapsed().as_secs_f64(),
+            cpu_time_s: usage.map(|(cpu, _)| cpu),
+            peak_rss_bytes: usage.map(|(_, rss)| rss),
+        }
+    }
+
+    pub(crate) fn log(&self) {
+        let cpu = self
+            .cpu_time_s
+            .map_or_else(|| "unknown".to_string(), |cpu| format!("{:.2}s", cpu));
+        let rss = self.peak_rss_bytes.map_or_else(
+            || "unknown".to_string(),
+            |rss| format!("{:.1} MiB", rss as f64 / (1024.0 * 1024.0)),
+        );
+        info!(
+            "Finished in {:.2}s wall time, {} CPU time, peak RSS {}",
+            self.wall_time_s, cpu, rss
+        );
+    }
+}
+
+/// What `--summary-json` records about a gather run.
+#[derive(Serialize)]
+pub(crate) struct RunSummary<'a> {
+    pub(crate) version: &'static str,
+    pub(crate) query: Option<&'a Path>,
+    pub(crate) query_list: Option<&'a Path>,
+    pub(crate) matchlist: Option<&'a Path>,
+    /// the `--db` matchlists and databases
+    #[serde(skip_serializing_if = "<[PathBuf]>::is_empty")]
+    pub(crate) db: &'a [PathBuf],
+    pub(crate) status: &'static str,
+    /// matchlist files that couldn't be loaded, even after retrying
+    pub(crate) failed_files: Vec<PathBuf>,
+    #[serde(flatten)]
+    pub(crate) metrics: RunMetrics,
+}
+
+impl RunSummary<'_> {
+    pub(crate) fn write(&self, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        let mut writer = output::create(path)?;
+        serde_json::to_writer_pretty(&mut writer, self)?;
+        writer.finish()?;
+        Ok(())
+    }
+}
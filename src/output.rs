@@ -0,0 +1,123 @@
+//! Output files that only appear under their final name once complete.
+//!
+//! Everything is written to `<path>.partial` and renamed into place by
+//! `finish`, so a crash (or a killed job) never leaves a truncated file
+//! that looks like a finished result; whatever was written so far stays
+//! in the `.partial` file.
+//!
+//! The exception is an output meant to be followed while it grows, like the
+//! gather CSV written a row per round: it is written in place, so a run
+//! that is interrupted leaves it truncated under its final name.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::Serialize;
+
+enum Inner {
+    Plain(BufWriter<File>),
+    Gzip(GzEncoder<BufWriter<File>>),
+}
+
+pub(crate) struct OutputFile {
+    inner: Inner,
+    /// where the file is written until it is finished, unless in place
+    partial: Option<PathBuf>,
+    path: PathBuf,
+}
+
+/// Where an output is written until it is finished.
+pub(crate) fn partial_path(path: &Path) -> PathBuf {
+    let mut partial = path.to_path_buf().into_os_string();
+    partial.push(".partial");
+    PathBuf::from(partial)
+}
+
+/// Open an output file, gzip-compressing it if the name ends in `.gz`.
+pub(crate) fn create<P: AsRef<Path>>(path: P) -> io::Result<OutputFile> {
+    let path = path.as_ref().to_path_buf();
+    let partial = partial_path(&path);
+    open(path, Some(partial))
+}
+
+/// Like `create`, but write the file under its final name from the start.
+pub(crate) fn create_in_place<P: AsRef<Path>>(path: P) -> io::Result<OutputFile> {
+    open(path.as_ref().to_path_buf(), None)
+}
+
+fn open(path: PathBuf, partial: Option<PathBuf>) -> io::Result<OutputFile> {
+    let file = BufWriter::new(File::create(partial.as_ref().unwrap_or(&path))?);
+    let inner = if path.extension().is_some_and(|ext| ext == "gz") {
+        Inner::Gzip(GzEncoder::new(file, Compression::default()))
+    } else {
+        Inner::Plain(file)
+    };
+    Ok(OutputFile {
+        inner,
+        partial,
+        path,
+    })
+}
+
+/// A CSV writer on top of an `OutputFile`.
+pub(crate) fn create_csv<P: AsRef<Path>>(path: P) -> io::Result<csv::Writer<OutputFile>> {
+    Ok(csv::Writer::from_writer(create(path)?))
+}
+
+/// A CSV writer on top of an `OutputFile` written in place.
+pub(crate) fn create_csv_in_place<P: AsRef<Path>>(path: P) -> io::Result<csv::Writer<OutputFile>> {
+    Ok(csv::Writer::from_writer(create_in_place(path)?))
+}
+
+/// Write the header row of a CSV of `T` rows, for a CSV that has none;
+/// serializing the first row would write it again.
+pub(crate) fn write_header<T: Serialize + Default, W: Write>(
+    writer: &mut csv::Writer<W>,
+) -> csv::Result<()> {
+    let mut scratch = csv::Writer::from_writer(vec![]);
+    scratch.serialize(T::default())?;
+    let data = scratch.into_inner().map_err(|e| e.into_error())?;
+    let header = csv::Reader::from_reader(data.as_slice()).headers()?.clone();
+    writer.write_record(&header)
+}
+
+/// Flush a CSV writer and move its file into place.
+pub(crate) fn finish_csv(writer: csv::Writer<OutputFile>) -> io::Result<()> {
+    writer.into_inner().map_err(|e| e.into_error())?.finish()
+}
+
+impl OutputFile {
+    /// Flush everything and rename the file to its final name, if it isn't
+    /// written in place.
+    pub(crate) fn finish(self) -> io::Result<()> {
+        let mut file = match self.inner {
+            Inner::Plain(file) => file,
+            Inner::Gzip(gz) => gz.finish()?,
+        };
+        file.flush()?;
+        file.get_ref().sync_all()?;
+        match &self.partial {
+            Some(partial) => std::fs::rename(partial, &self.path),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Write for OutputFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match &mut self.inner {
+            Inner::Plain(file) => file.write(buf),
+            Inner::Gzip(gz) => gz.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.inner {
+            Inner::Plain(file) => file.flush(),
+            Inner::Gzip(gz) => gz.flush(),
+        }
+    }
+}
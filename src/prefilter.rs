@@ -0,0 +1,261 @@
+//! A small index of heavily downsampled sketches, used to skip matchlist
+//! entries that can't reach the gather threshold without loading them.
+//!
+//! `countergather index` keeps every scaled sketch of each file
+//! downsampled by `--factor`; `--prefilter` estimates each entry's overlap
+//! with the query from those, and only loads entries that might pass.
+//!
+//! The summaries are downsampled hash sets, not HyperLogLog sketches: a
+//! HyperLogLog estimates set sizes, so the overlap with the query would
+//! come from the sizes of the union and of the two sets, which is far too
+//! noisy for overlaps that are a small fraction of either.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use clap::Args;
+use flate2::read::GzDecoder;
+use log::{info, warn};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use sourmash::signature::{Signature, SigsTrait};
+use sourmash::sketch::minhash::{max_hash_for_scaled, KmerMinHash};
+use sourmash::sketch::Sketch;
+
+use crate::{load_matchlist_paths, output};
+
+#[derive(Args, Debug)]
+pub struct IndexArgs {
+    /// file listing signature files to index
+    #[clap(parse(from_os_str))]
+    matchlist: PathBuf,
+
+    /// write the index to this file (gzipped if it ends in .gz)
+    #[clap(short = 'o', long = "output", parse(from_os_str))]
+    output: PathBuf,
+
+    /// downsample each sketch to this many times its scaled value
+    #[clap(long = "factor", default_value = "10")]
+    factor: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Summary {
+    ksize: u32,
+    moltype: String,
+    seed: u64,
+    scaled: u64,
+    hashes: Vec<u64>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct IndexEntry {
+    path: PathBuf,
+    size: u64,
+    mtime: u64,
+    sketches: Vec<Summary>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct PrefilterIndex {
+    factor: u64,
+    entries: Vec<IndexEntry>,
+}
+
+/// Size and modification time, to notice files changed since indexing.
+fn file_stamp(path: &Path) -> std::io::Result<(u64, u64)> {
+    let meta = std::fs::metadata(path)?;
+    let mtime = meta
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    Ok((meta.len(), mtime.as_secs()))
+}
+
+fn summarize(path: &Path, factor: u64) -> Result<IndexEntry, Box<dyn std::error::Error>> {
+    let (size, mtime) = file_stamp(path)?;
+    let mut sketches = vec![];
+    for sig in Signature::from_path(path)? {
+        for sketch in sig.sketches() {
+            if let Sketch::MinHash(mh) = sketch {
+                if mh.scaled() == 0 {
+                    continue;
+                }
+                let scaled = mh.scaled() * factor;
+                let small = mh.downsample_max_hash(max_hash_for_scaled(scaled))?;
+                sketches.push(Summary {
+                    ksize: mh.ksize() as u32,
+                    moltype: mh.hash_function().to_string(),
+                    seed: mh.seed(),
+                    scaled,
+                    hashes: small.mins(),
+                });
+            }
+        }
+    }
+    Ok(IndexEntry {
+        path: path.to_path_buf(),
+        size,
+        mtime,
+        sketches,
+    })
+}
+
+pub fn index(args: &IndexArgs) -> Result<(), Box<dyn std::error::Error>> {
+    if args.factor == 0 {
+        return Err("--factor must be at least 1".into());
+    }
+    let paths = load_matchlist_paths(&args.matchlist)?;
+    let entries: Vec<IndexEntry> = paths
+        .par_iter()
+        .filter_map(|path| match summarize(path, args.factor) {
+            Ok(entry) => Some(entry),
+            Err(e) => {
+                warn!("could not index {}: {}", path.display(), e);
+                None
+            }
+        })
+        .collect();
+
+    let index = PrefilterIndex {
+        factor: args.factor,
+        entries,
+    };
+    let mut writer = output::create(&args.output)?;
+    serde_json::to_writer(&mut writer, &index)?;
+    writer.finish()?;
+    info!(
+        "Indexed {} of {} files to {}",
+        index.entries.len(),
+        paths.len(),
+        args.output.display()
+    );
+    Ok(())
+}
+
+/// How many of the sorted hashes in `a` are also in the sorted `b`.
+fn count_sorted_common(a: &[u64], b: &[u64]) -> u64 {
+    let (mut i, mut j, mut n) = (0, 0, 0);
+    while i < a.len() && j < b.len() {
+        match a[i].cmp(&b[j]) {
+            std::cmp::Ordering::Less => i += 1,
+            std::cmp::Ordering::Greater => j += 1,
+            std::cmp::Ordering::Equal => {
+                n += 1;
+                i += 1;
+                j += 1;
+            }
+        }
+    }
+    n
+}
+
+impl PrefilterIndex {
+    pub(crate) fn load(path: &Path) -> Result<PrefilterIndex, Box<dyn std::error::Error>> {
+        let file = BufReader::new(File::open(path)?);
+        let reader: Box<dyn Read> = if path.extension().is_some_and(|ext| ext == "gz") {
+            Box::new(GzDecoder::new(file))
+        } else {
+            Box::new(file)
+        };
+        let index: PrefilterIndex = serde_json::from_reader(reader)
+            .map_err(|e| format!("{}: not a prefilter index: {}", path.display(), e))?;
+        info!(
+            "Loaded prefilter index {} ({} files, factor {})",
+            path.display(),
+            index.entries.len(),
+            index.factor
+        );
+        Ok(index)
+    }
+
+    /// Drop the paths whose summaries show they can't share `threshold_bp`
+    /// with the query. Paths missing from the index, changed since it was
+    /// built or without a summary compatible with the query are kept;
+    /// paths are looked up as written in the matchlist.
+    ///
+    /// An entry is dropped when, for each of its compatible summaries, the
+    /// summary overlap is more than three standard deviations below the
+    /// overlap expected at the threshold; a file with several signatures
+    /// is kept if any of them might pass.
+    pub(crate) fn filter(
+        &self,
+        paths: Vec<PathBuf>,
+        query: &KmerMinHash,
+        threshold_bp: u64,
+    ) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+        let by_path: HashMap<&Path, &IndexEntry> = self
+            .entries
+            .iter()
+            .map(|entry| (entry.path.as_path(), entry))
+            .collect();
+
+        // the query downsampled to each summary scaled in the index.
+        let mut queries: HashMap<u64, Vec<u64>> = HashMap::new();
+        for entry in &self.entries {
+            for summary in &entry.sketches {
+                if summary.scaled >= query.scaled() && !queries.contains_key(&summary.scaled) {
+                    let small = query.downsample_max_hash(max_hash_for_scaled(summary.scaled))?;
+                    queries.insert(summary.scaled, small.mins());
+                }
+            }
+        }
+
+        let moltype = query.hash_function().to_string();
+        let compatible = |summary: &&Summary| {
+            summary.ksize == query.ksize() as u32
+                && summary.moltype == moltype
+                && summary.seed == query.seed()
+                && queries.contains_key(&summary.scaled)
+        };
+
+        let n_paths = paths.len();
+        let mut n_stale = 0;
+        let mut kept = vec![];
+        for path in paths {
+            let entry = match by_path.get(path.as_path()) {
+                Some(entry) => entry,
+                None => {
+                    kept.push(path);
+                    continue;
+                }
+            };
+            if file_stamp(&path).ok() != Some((entry.size, entry.mtime)) {
+                n_stale += 1;
+                kept.push(path);
+                continue;
+            }
+            let mut summaries = entry.sketches.iter().filter(compatible).peekable();
+            if summaries.peek().is_none() {
+                kept.push(path);
+                continue;
+            }
+
+            let might_pass = summaries.any(|summary| {
+                let common = count_sorted_common(&queries[&summary.scaled], &summary.hashes) as f64;
+                let expected = threshold_bp as f64 / summary.scaled as f64;
+                common >= expected - 3.0 * expected.sqrt()
+            });
+            if might_pass {
+                kept.push(path);
+            }
+        }
+
+        if n_stale > 0 {
+            warn!(
+                "{} matchlist files changed since the prefilter index was built; loading them anyway",
+                n_stale
+            );
+        }
+        info!(
+            "Prefilter skipped {} of {} matchlist files",
+            n_paths - kept.len(),
+            n_paths
+        );
+        Ok(kept)
+    }
+}
@@ -0,0 +1,68 @@
+//! Overlap between the matches gather selected, to see how much each one
+//! shares with those ranked above it.
+
+use std::path::Path;
+
+use serde::Serialize;
+
+use sourmash::sketch::minhash::KmerMinHash;
+
+use crate::{intersect_hashes, output, Candidate, GatherResult};
+
+#[derive(Serialize)]
+struct RedundancyRow<'a> {
+    gather_rank: usize,
+    name: &'a str,
+    match_md5: &'a str,
+    earlier_rank: usize,
+    earlier_name: &'a str,
+    earlier_md5: &'a str,
+    query_md5: &'a str,
+    shared_bp: u64,
+    f_match_shared: f64,
+    shared_query_bp: u64,
+}
+
+/// For every pair of results, the hashes the later-ranked one shares with
+/// the earlier one, overall and within the original query. `matches`
+/// holds the match of each result, in the same order.
+///
+/// With `--max-memory` the matches only keep their query hashes, so both
+/// overlaps are within the query.
+pub(crate) fn write_redundancy_csv<P: AsRef<Path>>(
+    path: P,
+    results: &[GatherResult],
+    matches: &[Candidate],
+    orig_query: &KmerMinHash,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let scaled = orig_query.scaled();
+    let query_hashes = orig_query.mins();
+    let mut writer = output::create_csv(path)?;
+
+    for (i, result) in results.iter().enumerate() {
+        for (j, earlier) in results[..i].iter().enumerate() {
+            let shared = intersect_hashes(&matches[i].minhash, &matches[j].minhash);
+            if shared.is_empty() {
+                continue;
+            }
+            let in_query = shared
+                .iter()
+                .filter(|h| query_hashes.binary_search(h).is_ok())
+                .count() as u64;
+            writer.serialize(RedundancyRow {
+                gather_rank: result.gather_rank,
+                name: &result.name,
+                match_md5: &result.md5,
+                earlier_rank: earlier.gather_rank,
+                earlier_name: &earlier.name,
+                earlier_md5: &earlier.md5,
+                query_md5: &result.query_md5,
+                shared_bp: shared.len() as u64 * scaled,
+                f_match_shared: shared.len() as f64 / matches[i].match_size as f64,
+                shared_query_bp: in_query * scaled,
+            })?;
+        }
+    }
+    output::finish_csv(writer)?;
+    Ok(())
+}
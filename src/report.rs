@@ -0,0 +1,104 @@
+//! Human-friendly gather report, styled after `sourmash gather`.
+
+const BOLD: &str = "\x1b[1m";
+const GREEN: &str = "\x1b[32m";
+const RESET: &str = "\x1b[0m";
+
+/// One line of the report.
+pub struct ReportRow<'a> {
+    /// the whole overlap of the match with the original query, as
+    /// `intersect_bp`
+    pub overlap_bp: u64,
+    /// the part of the original query this match is the first to claim
+    pub p_query: f64,
+    /// the part of the match claimed in this round
+    pub p_match: f64,
+    /// containment ANI of the match in the original query
+    pub ani: f64,
+    pub name: &'a str,
+}
+
+/// Format a number of base pairs as bp/kbp/Mbp/Gbp.
+pub fn format_bp(bp: u64) -> String {
+    let bp = bp as f64;
+    if bp < 500.0 {
+        format!("{:.0} bp", bp)
+    } else if bp <= 500e3 {
+        format!("{:.1} kbp", bp / 1e3)
+    } else if bp < 500e6 {
+        format!("{:.1} Mbp", bp / 1e6)
+    } else {
+        format!("{:.1} Gbp", bp / 1e9)
+    }
+}
+
+/// Prints gather results to stdout as they are found.
+pub struct Report {
+    color: bool,
+    n_rows: usize,
+}
+
+impl Report {
+    pub fn new(color: bool) -> Report {
+        let report = Report { color, n_rows: 0 };
+        println!();
+        println!(
+            "{}overlap     p_query p_match   ANI{}",
+            report.bold(),
+            report.reset()
+        );
+        println!("---------   ------- -------  ------");
+        report
+    }
+
+    fn bold(&self) -> &'static str {
+        if self.color {
+            BOLD
+        } else {
+            ""
+        }
+    }
+
+    fn green(&self) -> &'static str {
+        if self.color {
+            GREEN
+        } else {
+            ""
+        }
+    }
+
+    fn reset(&self) -> &'static str {
+        if self.color {
+            RESET
+        } else {
+            ""
+        }
+    }
+
+    pub fn row(&mut self, row: &ReportRow) {
+        self.n_rows += 1;
+        println!(
+            "{:<9}   {:>6.1}%  {:>5.1}%  {:>5.1}%    {}{}{}",
+            format_bp(row.overlap_bp),
+            row.p_query * 100.0,
+            row.p_match * 100.0,
+            row.ani * 100.0,
+            self.green(),
+            row.name,
+            self.reset()
+        );
+    }
+
+    /// Print the totals line; `f_found` is the fraction of the query
+    /// assigned to some match.
+    pub fn finish(&self, f_found: f64) {
+        println!();
+        println!(
+            "found {} matches total;\nthe recovered matches hit {}{:.1}%{} of the query",
+            self.n_rows,
+            self.bold(),
+            f_found * 100.0,
+            self.reset()
+        );
+    }
+}
@@ -0,0 +1,185 @@
+//! The result of each gather round.
+
+use serde::Serialize;
+
+use sourmash::signature::SigsTrait;
+use sourmash::sketch::minhash::KmerMinHash;
+
+use crate::candidate::{jaccard, Candidate};
+
+/// One round of gather: the selected match and what it removed.
+#[derive(Serialize, Debug)]
+pub struct GatherResult {
+    pub gather_rank: usize,
+    /// with --per-contig, the query record this match was found for; the
+    /// column is only written then
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub contig: Option<String>,
+    pub name: String,
+    /// the first word of the match name, e.g. a GTDB accession
+    pub ident: String,
+    /// the matchlist file the match was loaded from; for databases, the
+    /// filename recorded in the signature
+    pub filename: String,
+    /// the matchlist or database the match is from
+    pub db: Option<String>,
+    /// the match's group in a CSV matchlist
+    pub group: Option<String>,
+    /// md5 of the match sketch as loaded, and of the sketch it was
+    /// downsampled from when loaded, if it was
+    pub md5: String,
+    pub match_source_md5: Option<String>,
+    /// md5 of the query sketch as used, and of the sketch it was
+    /// downsampled from if it was
+    pub query_md5: String,
+    pub query_source_md5: Option<String>,
+    /// the scaled the match was compared with the query at, after any
+    /// downsampling; the bp figures are hash counts times this
+    pub scaled: u64,
+    pub unique_intersect_hashes: u64,
+    pub f_unique_to_query: f64,
+    pub intersect_bp: u64,
+    pub unique_intersect_bp: u64,
+    pub f_orig_query: f64,
+    pub f_match: f64,
+    /// Jaccard similarity of the match and the original query
+    pub jaccard: f64,
+    pub remaining_bp: u64,
+    /// fraction of the original query assigned so far, including this round
+    pub f_found_cumulative: f64,
+    /// abundance-weighted query hashes assigned so far, for abundance queries
+    pub sum_weighted_found: Option<u64>,
+    pub total_weighted_hashes: Option<u64>,
+    /// estimated sequencing depth of the match, for abundance queries: the
+    /// query abundances of its hashes summed over its size, so that parts
+    /// missing from the query count as zero depth
+    pub coverage: Option<f64>,
+    /// with --derep-threshold, how many candidates the match stands for,
+    /// itself included
+    pub derep_members: Option<usize>,
+    /// with --fair-split, the rank of the first match of the co-equal group
+    /// this one is in, its share of the hashes the group claimed, and
+    /// those hashes in bp
+    pub split_group: Option<usize>,
+    pub split_fraction: Option<f64>,
+    pub split_unique_bp: Option<u64>,
+    /// whether query abundances were "used", "ignored" or absent ("none")
+    pub query_abundance: &'static str,
+}
+
+impl GatherResult {
+    /// Stats for `best` claiming `unique_intersect_hashes` of the
+    /// `query_size` hashes left in the query at round `gather_rank`;
+    /// `query_md5` is the md5 of `orig_query`.
+    pub fn new(
+        gather_rank: usize,
+        best: &Candidate,
+        unique_intersect_hashes: u64,
+        query_size: usize,
+        orig_query: &KmerMinHash,
+        query_md5: &str,
+    ) -> Result<GatherResult, sourmash::Error> {
+        let scaled = orig_query.scaled();
+        let orig_size = orig_query.size() as f64;
+        let intersect_hashes = best.minhash.count_common(orig_query, false)?;
+        Ok(GatherResult {
+            gather_rank,
+            contig: None,
+            name: best.name.clone(),
+            ident: ident_for_name(&best.name).to_string(),
+            filename: best.filename.clone(),
+            db: best.db.clone(),
+            group: best.group.clone(),
+            md5: best.md5.clone(),
+            match_source_md5: best.source_md5.clone(),
+            query_md5: query_md5.to_string(),
+            query_source_md5: None,
+            scaled,
+            unique_intersect_hashes,
+            f_unique_to_query: unique_intersect_hashes as f64 / orig_size,
+            intersect_bp: intersect_hashes * scaled,
+            unique_intersect_bp: unique_intersect_hashes * scaled,
+            f_orig_query: intersect_hashes as f64 / orig_size,
+            f_match: intersect_hashes as f64 / best.match_size as f64,
+            jaccard: jaccard(intersect_hashes, orig_query.size(), best.match_size),
+            remaining_bp: (query_size as u64 - unique_intersect_hashes) * scaled,
+            f_found_cumulative: 1.0
+                - (query_size as u64 - unique_intersect_hashes) as f64 / orig_size,
+            sum_weighted_found: None,
+            total_weighted_hashes: None,
+            coverage: None,
+            derep_members: None,
+            split_group: None,
+            split_fraction: None,
+            split_unique_bp: None,
+            query_abundance: if orig_query.track_abundance() {
+                "used"
+            } else {
+                "none"
+            },
+        })
+    }
+
+    /// Credit the match with `share` of the hashes its `--fair-split` group
+    /// claimed instead of the ones it claimed greedily; `remaining` query
+    /// hashes are left after the members so far.
+    pub fn split(&mut self, share: f64, remaining: f64, orig_size: usize) {
+        let orig_size = orig_size as f64;
+        let scaled = self.scaled as f64;
+        self.unique_intersect_hashes = share.round() as u64;
+        self.f_unique_to_query = share / orig_size;
+        self.unique_intersect_bp = (share * scaled).round() as u64;
+        self.split_unique_bp = Some(self.unique_intersect_bp);
+        self.remaining_bp = (remaining * scaled).round() as u64;
+        self.f_found_cumulative = 1.0 - remaining / orig_size;
+    }
+}
+
+/// The identifier of a match is the first word of its name.
+pub fn ident_for_name(name: &str) -> &str {
+    name.split_whitespace().next().unwrap_or("")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use sourmash::encodings::HashFunctions;
+
+    fn minhash(hashes: &[u64]) -> KmerMinHash {
+        let mut mh = KmerMinHash::new(1000, 31, HashFunctions::murmur64_DNA, 42, false, 0);
+        mh.add_many(hashes).unwrap();
+        mh
+    }
+
+    #[test]
+    fn split_credits_the_share() {
+        let query = minhash(&[1, 2, 3, 4, 5, 6, 7, 8]);
+        let best = Candidate::new("a".into(), minhash(&[1, 2, 3, 4]), 4);
+        let mut result = GatherResult::new(0, &best, 4, 8, &query, "").unwrap();
+        assert_eq!(result.unique_intersect_bp, 4000);
+
+        // two hashes of its own, one shared by two members, one by three.
+        let share = 2.0 + 1.0 / 2.0 + 1.0 / 3.0;
+        result.split(share, 8.0 - share, 8);
+        assert_eq!(result.unique_intersect_hashes, 3);
+        assert_eq!(result.unique_intersect_bp, 2833);
+        assert_eq!(result.split_unique_bp, Some(2833));
+        assert_eq!(result.f_unique_to_query, share / 8.0);
+        assert_eq!(result.remaining_bp, 5167);
+        assert_eq!(result.f_found_cumulative, 1.0 - (8.0 - share) / 8.0);
+        // the whole overlap is still reported as it is.
+        assert_eq!(result.intersect_bp, 4000);
+    }
+
+    #[test]
+    fn split_rounds_half_shares_up() {
+        let query = minhash(&[1, 2, 3, 4]);
+        let best = Candidate::new("a".into(), minhash(&[1, 2, 3]), 3);
+        let mut result = GatherResult::new(0, &best, 3, 4, &query, "").unwrap();
+        result.split(2.5, 1.5, 4);
+        assert_eq!(result.unique_intersect_hashes, 3);
+        assert_eq!(result.unique_intersect_bp, 2500);
+        assert_eq!(result.remaining_bp, 1500);
+    }
+}
@@ -0,0 +1,98 @@
+//! Reading the leaf signatures of a sourmash SBT saved as a `.sbt.zip`.
+//!
+//! The internal nodes (Bloom filters) are not used; every leaf is loaded
+//! and goes through the same prefetch as a flat matchlist.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use rayon::prelude::*;
+use serde::Deserialize;
+use sourmash::signature::Signature;
+
+#[derive(Deserialize)]
+struct SbtNode {
+    filename: String,
+}
+
+#[derive(Deserialize)]
+struct SbtStorageArgs {
+    #[serde(default)]
+    path: String,
+}
+
+#[derive(Deserialize)]
+struct SbtStorage {
+    args: SbtStorageArgs,
+}
+
+#[derive(Deserialize)]
+struct SbtDescription {
+    storage: SbtStorage,
+    #[serde(default)]
+    nodes: HashMap<String, SbtNode>,
+    /// Present from SBT format v5 on; older versions keep leaves in `nodes`.
+    leaves: Option<HashMap<String, SbtNode>>,
+}
+
+pub(crate) fn is_sbt_zip<P: AsRef<Path>>(path: P) -> bool {
+    path.as_ref()
+        .file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| name.ends_with(".sbt.zip"))
+}
+
+/// Load the signatures of every leaf in the SBT.
+pub(crate) fn load_leaves(path: &Path) -> Result<Vec<Signature>, Box<dyn std::error::Error>> {
+    let mut archive = zip::ZipArchive::new(File::open(path)?)?;
+
+    let sbt_name = archive
+        .file_names()
+        .find(|name| name.ends_with(".sbt.json"))
+        .map(str::to_string)
+        .ok_or_else(|| format!("{}: no .sbt.json in archive", path.display()))?;
+    let description: SbtDescription = serde_json::from_reader(archive.by_name(&sbt_name)?)?;
+
+    let leaves: Vec<String> = match description.leaves {
+        Some(leaves) => leaves.into_values().map(|n| n.filename).collect(),
+        None => description
+            .nodes
+            .into_values()
+            .map(|n| n.filename)
+            .filter(|f| !f.starts_with("internal"))
+            .collect(),
+    };
+
+    // leaves are stored next to the .sbt.json, optionally under the
+    // storage subdirectory.
+    let entries: Vec<String> = archive.file_names().map(str::to_string).collect();
+    // every entry by its full name and, unless that is taken, by the part
+    // after its last `/`.
+    let mut by_name: HashMap<&str, &String> = entries.iter().map(|e| (e.as_str(), e)).collect();
+    for entry in &entries {
+        if let Some((_, base)) = entry.rsplit_once('/') {
+            by_name.entry(base).or_insert(entry);
+        }
+    }
+    let storage_dir = description.storage.args.path.trim_start_matches("./");
+    let mut contents = vec![];
+    for leaf in leaves {
+        let in_storage = format!("{}/{}", storage_dir, leaf);
+        let entry = by_name
+            .get(in_storage.as_str())
+            .or_else(|| by_name.get(leaf.as_str()))
+            .ok_or_else(|| format!("{}: leaf {} is missing", path.display(), leaf))?;
+
+        let mut data = vec![];
+        archive.by_name(entry)?.read_to_end(&mut data)?;
+        contents.push(data);
+    }
+
+    let sigs: Result<Vec<Vec<Signature>>, _> = contents
+        .par_iter()
+        .map(|data| Signature::from_reader(&data[..]))
+        .collect();
+    Ok(sigs?.into_iter().flatten().collect())
+}
@@ -0,0 +1,71 @@
+//! A hash set of the query's hashes, for screening matchlist sketches as
+//! they are loaded.
+//!
+//! Screening walks a candidate's hashes once, counting those in the query,
+//! and stops as soon as the threshold can no longer be reached with the
+//! hashes left; most non-matching sketches are rejected without a full
+//! comparison, and the count of the others is their overlap, so they are
+//! not compared again.
+
+use std::collections::HashSet;
+use std::hash::{BuildHasherDefault, Hasher};
+
+use sourmash::signature::SigsTrait;
+use sourmash::sketch::minhash::KmerMinHash;
+
+/// Sketch hashes are already uniformly distributed; use them as-is.
+#[derive(Default)]
+struct IdentityHasher(u64);
+
+impl Hasher for IdentityHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for b in bytes {
+            self.0 = (self.0 << 8) | u64::from(*b);
+        }
+    }
+
+    fn write_u64(&mut self, n: u64) {
+        self.0 = n;
+    }
+}
+
+pub(crate) struct QueryScreen<'a> {
+    query: &'a KmerMinHash,
+    hashes: HashSet<u64, BuildHasherDefault<IdentityHasher>>,
+}
+
+impl<'a> QueryScreen<'a> {
+    pub(crate) fn new(query: &'a KmerMinHash) -> QueryScreen<'a> {
+        QueryScreen {
+            query,
+            hashes: query.iter_mins().copied().collect(),
+        }
+    }
+
+    pub(crate) fn query(&self) -> &'a KmerMinHash {
+        self.query
+    }
+
+    /// How many hashes `mh` shares with the query, if at least
+    /// `threshold_hashes`. A sketch coarser than the query is compared
+    /// with the query's hashes in its own range, as if the query were
+    /// downsampled to it.
+    pub(crate) fn overlap(&self, mh: &KmerMinHash, threshold_hashes: u64) -> Option<u64> {
+        let mut remaining = mh.size() as u64;
+        let mut found = 0;
+        for hash in mh.iter_mins() {
+            if found + remaining < threshold_hashes {
+                return None;
+            }
+            remaining -= 1;
+            if self.hashes.contains(hash) {
+                found += 1;
+            }
+        }
+        (found >= threshold_hashes).then_some(found)
+    }
+}
@@ -0,0 +1,263 @@
+//! `countergather search`: every matchlist sketch whose containment of the
+//! query, max containment or Jaccard similarity reaches a threshold.
+//!
+//! This is the prefetch step on its own, with the threshold given as a
+//! fraction rather than in bp; no matches are removed from the query.
+
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use clap::{ArgMatches, Args, ValueSource};
+use log::{info, warn};
+use rayon::prelude::*;
+use serde::Serialize;
+
+use sourmash::signature::SigsTrait;
+
+use crate::{
+    build_template, database_candidates, is_database, jaccard, load_candidate, load_matchlists,
+    load_query_sigs, loader, output, screen, select_query, use_threads, Candidate, GatherArgs,
+    GatherStatus, Matchlist,
+};
+
+#[derive(Args, Debug)]
+pub struct SearchArgs {
+    #[clap(flatten)]
+    pub(crate) gather: GatherArgs,
+
+    /// report matches scoring at least this by --metric
+    #[clap(long = "threshold", default_value = "0.1")]
+    threshold: f64,
+}
+
+/// The gather options search has no use for.
+const IGNORED: &[&str] = &[
+    "output-dir",
+    "skip-completed",
+    "deterministic",
+    "ignore-abundance",
+    "report",
+    "color",
+    "max-memory",
+    "lazy-load",
+    "no-prefetch",
+    "lazy-cache-size",
+    "speculate",
+    "prune-contained",
+    "derep-threshold",
+    "fair-split",
+    "coarse-scaled",
+    "early-start-bp",
+    "load-rate",
+    "prefilter",
+    "min-unique-bp",
+    "stop-at-fraction",
+    "rank-by",
+    "threshold-bp",
+    "sort-by",
+    "output-prefetch",
+    "no-gather",
+    "output-hashes",
+    "output-redundancy",
+    "output-derep",
+    "output-unassigned",
+    "output-found",
+    "save-query-every",
+    "save-query-prefix",
+    "save-round-diffs",
+    "explain-match",
+    "explain-prefix",
+    "compat",
+    "summary-json",
+    "gzip-output",
+    "taxonomy",
+];
+
+/// Refuse the gather options given to search that it would ignore, rather
+/// than run without them.
+pub(crate) fn reject_ignored(matches: &ArgMatches) -> Result<(), String> {
+    let given: Vec<String> = IGNORED
+        .iter()
+        .filter(|id| {
+            matches!(
+                matches.value_source(id),
+                Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+            )
+        })
+        .map(|id| format!("--{}", id))
+        .collect();
+    if given.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("search does not support {}", given.join(", ")))
+    }
+}
+
+#[derive(Serialize)]
+struct SearchRow<'a> {
+    similarity: f64,
+    name: String,
+    filename: &'a str,
+    db: Option<&'a str>,
+    group: Option<&'a str>,
+    md5: &'a str,
+    query_md5: &'a str,
+    scaled: u64,
+    intersect_hashes: u64,
+    intersect_bp: u64,
+    match_hashes: usize,
+    f_query: f64,
+    f_match: f64,
+    max_containment: f64,
+    jaccard: f64,
+}
+
+pub fn search(args: &SearchArgs) -> Result<GatherStatus, Box<dyn std::error::Error>> {
+    let gather = &args.gather;
+    let query_filename = match (&gather.query, &gather.query_list) {
+        (Some(query), None) => query,
+        _ => return Err("search needs a query and a matchlist, not --query-list".into()),
+    };
+    if !(0.0..=1.0).contains(&args.threshold) {
+        return Err("--threshold must be between 0 and 1".into());
+    }
+    if gather.per_contig {
+        return Err("search does not support --per-contig".into());
+    }
+    if gather.auto_downsample {
+        return Err("search does not support --auto-downsample".into());
+    }
+    use_threads(gather.threads);
+
+    let sigs = load_query_sigs(query_filename, gather)?;
+    let template = build_template(
+        &sigs,
+        gather.template.ksize,
+        gather.template.scaled,
+        gather.template.moltype()?,
+        gather.template.seed,
+    )?;
+    let (_, query, _) = select_query(&sigs, &template)?;
+    if query.size() == 0 {
+        info!("Query sketch is empty, nothing to search for.");
+        return Ok(GatherStatus::NoMatches);
+    }
+    let query_md5 = query.md5sum();
+
+    // neither containment nor Jaccard similarity can reach the threshold
+    // with fewer shared hashes than this; a small enough match reaches any
+    // max-containment with a single one.
+    let threshold_hashes = match gather.metric.as_str() {
+        "max-containment" => 1,
+        _ => ((args.threshold * query.size() as f64).ceil() as u64).max(1),
+    };
+
+    let (databases, lists): (Vec<&PathBuf>, Vec<&PathBuf>) = gather
+        .matchlist
+        .iter()
+        .chain(&gather.db)
+        .partition(|path| is_database(path));
+    let Matchlist { paths, labels } = load_matchlists(&lists)?;
+
+    let cache_dir = gather.cache_dir.as_deref();
+    if let Some(dir) = cache_dir {
+        std::fs::create_dir_all(dir)?;
+    }
+    let failed_files = Mutex::new(vec![]);
+    let loader = loader::Loader::new(cache_dir, gather.retry_policy(), &failed_files);
+    let screen = screen::QueryScreen::new(&query);
+    let mut matches: Vec<Candidate> = paths
+        .par_iter()
+        .filter_map(|path| {
+            load_candidate(path, &template, &screen, threshold_hashes, false, &loader)
+                .map(|result| result.labelled(labels.get(path)))
+        })
+        .collect();
+    for db in &databases {
+        matches.extend(database_candidates(
+            db,
+            &template,
+            &screen,
+            threshold_hashes,
+            false,
+        )?);
+    }
+
+    let n_failed = loader.n_failed();
+    if n_failed > 0 {
+        warn!(
+            "{} of {} matchlist entries could not be loaded",
+            n_failed,
+            paths.len()
+        );
+    }
+
+    let query_size = query.size() as f64;
+    let mut rows: Vec<SearchRow> = matches
+        .iter()
+        .filter_map(|result| {
+            let overlap = result.containment as f64;
+            let match_size = result.match_size as f64;
+            let f_query = overlap / query_size;
+            let max_containment = overlap / query_size.min(match_size);
+            let jaccard = jaccard(result.containment, query.size(), result.match_size);
+            let similarity = match gather.metric.as_str() {
+                "max-containment" => max_containment,
+                "jaccard" => jaccard,
+                _ => f_query,
+            };
+            (similarity >= args.threshold).then(|| SearchRow {
+                similarity,
+                name: gather.format_name(&result.name),
+                filename: &result.filename,
+                db: result.db.as_deref(),
+                group: result.group.as_deref(),
+                md5: &result.md5,
+                query_md5: &query_md5,
+                scaled: query.scaled(),
+                intersect_hashes: result.containment,
+                intersect_bp: result.containment * query.scaled(),
+                match_hashes: result.match_size,
+                f_query,
+                f_match: overlap / match_size,
+                max_containment,
+                jaccard,
+            })
+        })
+        .collect();
+    rows.sort_by(|a, b| {
+        b.similarity
+            .total_cmp(&a.similarity)
+            .then_with(|| a.name.cmp(&b.name))
+    });
+
+    info!(
+        "Found {} matches with {} at least {}",
+        rows.len(),
+        gather.metric,
+        args.threshold
+    );
+    match &gather.output {
+        Some(path) => {
+            let mut writer = output::create_csv(path)?;
+            for row in &rows {
+                writer.serialize(row)?;
+            }
+            output::finish_csv(writer)?;
+            info!("Wrote {} matches to {}", rows.len(), path.display());
+        }
+        None => {
+            for row in &rows {
+                println!("{:>6.1}%  {}", row.similarity * 100.0, row.name);
+            }
+        }
+    }
+
+    Ok(if n_failed > 0 {
+        GatherStatus::PartialLoad
+    } else if rows.is_empty() {
+        GatherStatus::NoMatches
+    } else {
+        GatherStatus::Matched
+    })
+}
@@ -0,0 +1,269 @@
+//! Long-running HTTP server that keeps the matchlist loaded in memory.
+//!
+//! Endpoints:
+//!
+//! * `GET /` - database summary
+//! * `POST /gather` - body is a signature file (JSON, optionally gzipped);
+//!   responds with the gather results as JSON.
+
+use std::path::{Path, PathBuf};
+
+use clap::Args;
+use log::{debug, info, trace, warn};
+use rayon::prelude::*;
+use serde::Serialize;
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use sourmash::encodings::HashFunctions;
+use sourmash::signature::{Signature, SigsTrait};
+use sourmash::sketch::minhash::KmerMinHash;
+use sourmash::sketch::Sketch;
+
+use crate::{
+    compute, engine, ksize_of, load_matchlist_paths, prepare_query_with_source, select_query,
+    Candidate, GatherResult,
+};
+
+#[derive(Args, Debug)]
+pub struct ServeArgs {
+    /// matchlist file, or directory of signature files
+    #[clap(long = "db", parse(from_os_str))]
+    pub(crate) db: PathBuf,
+
+    /// address to listen on
+    #[clap(long = "host", default_value = "127.0.0.1")]
+    pub(crate) host: String,
+
+    /// port to listen on
+    #[clap(long = "port", default_value = "8080")]
+    pub(crate) port: u16,
+
+    /// k-mer size
+    #[clap(short = 'k', long = "ksize", default_value = "31")]
+    ksize: u32,
+
+    /// scaled value
+    #[clap(short = 's', long = "scaled", default_value = "1000")]
+    scaled: u64,
+
+    /// molecule type
+    #[clap(
+        long = "moltype",
+        default_value = "DNA",
+        possible_values = &["DNA", "protein", "dayhoff", "hp"]
+    )]
+    moltype: String,
+
+    /// hash seed
+    #[clap(long = "seed", default_value = "42")]
+    seed: u64,
+
+    /// minimum overlap with the query, in bp, for a match to be reported
+    #[clap(long = "threshold-bp", default_value = "0")]
+    pub(crate) threshold_bp: u64,
+
+    /// number of requests to answer at once; by default, one per CPU
+    #[clap(long = "workers")]
+    workers: Option<usize>,
+}
+
+impl ServeArgs {
+    /// The template sketch that database and queries are prepared with.
+    pub(crate) fn template(&self) -> Result<KmerMinHash, sourmash::Error> {
+        let hash_function = HashFunctions::try_from(self.moltype.as_str())?;
+        Ok(compute::empty_minhash(
+            self.ksize,
+            self.scaled,
+            hash_function,
+            self.seed,
+            false,
+        ))
+    }
+}
+
+#[derive(Serialize)]
+struct DbInfo {
+    sketches: usize,
+    ksize: u32,
+    scaled: u64,
+    moltype: String,
+    seed: u64,
+}
+
+#[derive(Serialize)]
+struct GatherResponse {
+    query: String,
+    results: Vec<GatherResult>,
+}
+
+/// The signature files making up the database.
+pub(crate) fn db_paths(db: &Path) -> Result<Vec<PathBuf>, std::io::Error> {
+    if db.is_dir() {
+        let mut paths: Vec<PathBuf> = std::fs::read_dir(db)?
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .filter(|path| {
+                let name = path.to_string_lossy();
+                name.ends_with(".sig") || name.ends_with(".sig.gz")
+            })
+            .collect();
+        paths.sort();
+        Ok(paths)
+    } else {
+        load_matchlist_paths(db)
+    }
+}
+
+/// Load every sketch compatible with the template, regardless of query.
+pub(crate) fn load_db(paths: &[PathBuf], template: &Sketch) -> Vec<Candidate> {
+    paths
+        .par_iter()
+        .flat_map_iter(|path| {
+            let sigs = match Signature::from_path(path) {
+                Ok(sigs) => sigs,
+                Err(e) => {
+                    warn!("could not load {}: {}", path.display(), e);
+                    vec![]
+                }
+            };
+            sigs.into_iter()
+                .filter_map(|sig| match prepare_query_with_source(&sig, template) {
+                    Ok((minhash, source)) => Some(
+                        Candidate::new(sig.name(), minhash, 0)
+                            .with_filename(path.display().to_string())
+                            .with_source(source.as_ref()),
+                    ),
+                    Err(e) => {
+                        debug!("{}: skipping {}: {}", path.display(), sig.name(), e);
+                        None
+                    }
+                })
+        })
+        .collect()
+}
+
+/// A body that can't be serialized is answered with an error, never with an
+/// empty body and a success status.
+fn json_response<T: Serialize>(status: u16, body: &T) -> Response<std::io::Cursor<Vec<u8>>> {
+    let (status, body) = match serde_json::to_vec(body) {
+        Ok(body) => (status, body),
+        Err(e) => {
+            let message = format!("could not serialize the response: {}", e);
+            (
+                500,
+                serde_json::json!({ "error": message })
+                    .to_string()
+                    .into_bytes(),
+            )
+        }
+    };
+    let header = Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..]).unwrap();
+    Response::from_data(body)
+        .with_status_code(status)
+        .with_header(header)
+}
+
+fn error_response(status: u16, message: &str) -> Response<std::io::Cursor<Vec<u8>>> {
+    json_response(status, &serde_json::json!({ "error": message }))
+}
+
+fn handle_gather(
+    request: &mut Request,
+    template: &Sketch,
+    db: &[Candidate],
+    threshold_bp: u64,
+) -> Response<std::io::Cursor<Vec<u8>>> {
+    let sigs = match Signature::from_reader(request.as_reader()) {
+        Ok(sigs) => sigs,
+        Err(e) => return error_response(400, &format!("could not parse signature: {}", e)),
+    };
+
+    let (query_name, query, query_source) = match select_query(&sigs, template) {
+        Ok(query) => query,
+        Err(e) => return error_response(422, &e.to_string()),
+    };
+
+    // the database is already loaded, so there is no load progress.
+    let on_event = |event: engine::GatherEvent| match event {
+        engine::GatherEvent::CandidateFound(candidate) => {
+            trace!("{}: candidate {}", query_name, candidate.name)
+        }
+        engine::GatherEvent::RoundComplete {
+            result,
+            remaining_hashes,
+            candidates,
+        } => debug!(
+            "{}: round {} picked {}; {} hashes and {} candidates left",
+            query_name, result.gather_rank, result.name, remaining_hashes, candidates
+        ),
+        _ => {}
+    };
+    match engine::heap_gather(query, db, threshold_bp, on_event) {
+        Ok(mut results) => {
+            info!("{}: {} matches", query_name, results.len());
+            let query_source_md5 = query_source.map(|mh| mh.md5sum());
+            for result in &mut results {
+                result.query_source_md5 = query_source_md5.clone();
+            }
+            json_response(
+                200,
+                &GatherResponse {
+                    query: query_name,
+                    results,
+                },
+            )
+        }
+        Err(e) => error_response(500, &e.to_string()),
+    }
+}
+
+pub fn serve(args: &ServeArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let template_mh = args.template()?;
+    let info = DbInfo {
+        sketches: 0,
+        ksize: ksize_of(&template_mh),
+        scaled: template_mh.scaled(),
+        moltype: template_mh.hash_function().to_string(),
+        seed: template_mh.seed(),
+    };
+    let template = Sketch::MinHash(template_mh);
+
+    info!("Loading database from {}", args.db.display());
+    let paths = db_paths(&args.db)?;
+    let db = load_db(&paths, &template);
+    info!("Loaded {} sketches from {} files", db.len(), paths.len());
+
+    let info = DbInfo {
+        sketches: db.len(),
+        ..info
+    };
+
+    let address = format!("{}:{}", args.host, args.port);
+    let server = Server::http(&address).map_err(|e| e.to_string())?;
+    let workers = args
+        .workers
+        .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get()));
+    info!("Listening on http://{} with {} workers", address, workers);
+
+    // each worker takes the next request as soon as it is free, so a slow
+    // gather doesn't hold up the others.
+    std::thread::scope(|scope| {
+        for _ in 0..workers.max(1) {
+            scope.spawn(|| {
+                for mut request in server.incoming_requests() {
+                    let response = match (request.method(), request.url()) {
+                        (Method::Get, "/") => json_response(200, &info),
+                        (Method::Post, "/gather") => {
+                            handle_gather(&mut request, &template, &db, args.threshold_bp)
+                        }
+                        _ => error_response(404, "not found"),
+                    };
+                    if let Err(e) = request.respond(response) {
+                        warn!("could not send response: {}", e);
+                    }
+                }
+            });
+        }
+    });
+
+    Ok(())
+}
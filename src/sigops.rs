@@ -0,0 +1,112 @@
+//! `countergather intersect`, `subtract` and `merge`: hash set arithmetic
+//! on the compatible sketches of several signature files.
+//!
+//! Sketches with different scaled values are all downsampled to the
+//! coarsest of them first. Abundances come from the first signature for
+//! `intersect` and `subtract`, and are summed by `merge`.
+
+use std::path::PathBuf;
+
+use clap::Args;
+use log::info;
+
+use sourmash::signature::{Signature, SigsTrait};
+use sourmash::sketch::minhash::KmerMinHash;
+
+use crate::{
+    build_template, claimed_by, prepare_query_any_scaled, write_minhash_sig, PrepareQueryError,
+    TemplateArgs,
+};
+
+#[derive(Args, Debug)]
+pub struct SigOpArgs {
+    /// signature files; `subtract` removes the others from the first one
+    #[clap(parse(from_os_str), required = true, min_values = 2)]
+    sigs: Vec<PathBuf>,
+
+    #[clap(flatten)]
+    template: TemplateArgs,
+
+    /// write the result to this signature file (gzipped if it ends in .gz)
+    #[clap(short = 'o', long = "output", parse(from_os_str))]
+    output: PathBuf,
+
+    /// name of the result; by default, the name of the first signature
+    #[clap(long = "name")]
+    name: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum SigOp {
+    Intersect,
+    Subtract,
+    Merge,
+}
+
+/// The compatible sketch of every file, all at the same scaled, and the
+/// name of the first signature.
+fn load_sketches(
+    args: &SigOpArgs,
+) -> Result<(String, Vec<KmerMinHash>), Box<dyn std::error::Error>> {
+    let first = Signature::from_path(&args.sigs[0])?;
+    let template = build_template(
+        &first,
+        args.template.ksize,
+        args.template.scaled,
+        args.template.moltype()?,
+        args.template.seed,
+    )?;
+
+    let mut name = None;
+    let mut sketches = vec![];
+    for path in &args.sigs {
+        let sigs = Signature::from_path(path)?;
+        let mut prepared = Err(PrepareQueryError::NoCompatibleSketch(vec![]));
+        for sig in &sigs {
+            prepared = prepare_query_any_scaled(sig, &template).map(|mh| (sig.name(), mh));
+            if prepared.is_ok() {
+                break;
+            }
+        }
+        let (sig_name, mh) = prepared.map_err(|e| format!("{}: {}", path.display(), e))?;
+        name.get_or_insert(sig_name);
+        sketches.push(mh);
+    }
+
+    let max_hash = sketches.iter().map(|mh| mh.max_hash()).min().unwrap();
+    let sketches = sketches
+        .into_iter()
+        .map(|mh| {
+            if mh.max_hash() == max_hash {
+                Ok(mh)
+            } else {
+                mh.downsample_max_hash(max_hash)
+            }
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok((name.unwrap(), sketches))
+}
+
+pub fn run(op: SigOp, args: &SigOpArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let (first_name, sketches) = load_sketches(args)?;
+    let (first, rest) = sketches.split_first().unwrap();
+
+    let mut result = first.clone();
+    for other in rest {
+        match op {
+            SigOp::Intersect => result = claimed_by(&result, other)?,
+            SigOp::Subtract => result.remove_from(other)?,
+            SigOp::Merge => result.merge(other)?,
+        }
+    }
+
+    let name = args.name.clone().unwrap_or(first_name);
+    write_minhash_sig(&args.output, &name, &result)?;
+    info!(
+        "Wrote {} hashes from {} signatures to {}",
+        result.size(),
+        sketches.len(),
+        args.output.display()
+    );
+    Ok(())
+}
@@ -0,0 +1,262 @@
+//! Picking single signatures out of a sourmash zip collection, without
+//! unpacking it, and writing such collections.
+//!
+//! A selection is written `collection.zip::<selector>`, where the selector
+//! is a signature name or (a prefix of) a sketch md5. If the archive has a
+//! `SOURMASH-MANIFEST.csv`, only the entries it points to are read.
+
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use log::debug;
+use serde::{Deserialize, Serialize};
+use sourmash::signature::{Signature, SigsTrait};
+use sourmash::sketch::minhash::KmerMinHash;
+use sourmash::sketch::Sketch;
+
+use crate::{compat, ksize_of, output};
+
+const MANIFEST: &str = "SOURMASH-MANIFEST.csv";
+
+#[derive(Deserialize)]
+struct ManifestRow {
+    internal_location: String,
+    md5: String,
+    name: String,
+}
+
+/// Split `collection.zip::selector` into its archive and selector.
+pub(crate) fn parse_selection(query: &Path) -> Option<(&Path, &str)> {
+    let query = query.to_str()?;
+    let (zip, selector) = query.split_once(".zip::")?;
+    let zip = &query[..zip.len() + ".zip".len()];
+    Some((Path::new(zip), selector))
+}
+
+fn selects(selector: &str, name: &str, md5: &str) -> bool {
+    name == selector || (!selector.is_empty() && md5.starts_with(selector))
+}
+
+/// The manifest's entries for the selection (all of them without a
+/// selector), or `None` if the archive has no manifest.
+fn manifest_entries(
+    archive: &mut zip::ZipArchive<File>,
+    selector: Option<&str>,
+) -> Result<Option<Vec<String>>, Box<dyn std::error::Error>> {
+    let entry = match archive.by_name(MANIFEST) {
+        Ok(entry) => entry,
+        Err(zip::result::ZipError::FileNotFound) => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+
+    // the manifest starts with a `# SOURMASH-MANIFEST-VERSION` comment.
+    let text: String = BufReader::new(entry)
+        .lines()
+        .collect::<Result<Vec<_>, _>>()?
+        .into_iter()
+        .filter(|line| !line.starts_with('#'))
+        .collect::<Vec<_>>()
+        .join("\n");
+    let mut reader = csv::Reader::from_reader(text.as_bytes());
+
+    let mut entries = vec![];
+    for row in reader.deserialize() {
+        let row: ManifestRow = row?;
+        let selected = selector.is_none_or(|selector| selects(selector, &row.name, &row.md5));
+        if selected && !entries.contains(&row.internal_location) {
+            entries.push(row.internal_location);
+        }
+    }
+    Ok(Some(entries))
+}
+
+/// Is this a sourmash zip collection (and not an SBT)?
+pub(crate) fn is_collection(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| name.ends_with(".zip") && !name.ends_with(".sbt.zip"))
+}
+
+/// The signature files in the archive to read for the selection.
+fn archive_entries(
+    archive: &mut zip::ZipArchive<File>,
+    zip_path: &Path,
+    selector: Option<&str>,
+) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    match manifest_entries(archive, selector)? {
+        Some(entries) => Ok(entries),
+        None => {
+            debug!(
+                "{} has no manifest; reading every signature",
+                zip_path.display()
+            );
+            Ok(archive
+                .file_names()
+                .filter(|name| name.ends_with(".sig") || name.ends_with(".sig.gz"))
+                .map(str::to_string)
+                .collect())
+        }
+    }
+}
+
+fn read_entry(
+    archive: &mut zip::ZipArchive<File>,
+    entry: &str,
+) -> Result<Vec<Signature>, Box<dyn std::error::Error>> {
+    let mut data = vec![];
+    archive.by_name(entry)?.read_to_end(&mut data)?;
+    Ok(Signature::from_reader(&data[..])?)
+}
+
+/// Load every signature in the archive.
+pub(crate) fn load_all(zip_path: &Path) -> Result<Vec<Signature>, Box<dyn std::error::Error>> {
+    let mut archive = zip::ZipArchive::new(File::open(zip_path)?)?;
+    let mut sigs = vec![];
+    for entry in archive_entries(&mut archive, zip_path, None)? {
+        sigs.extend(read_entry(&mut archive, &entry)?);
+    }
+    Ok(sigs)
+}
+
+/// Load the signatures in the archive that match the selector, keeping
+/// only their matching sketches when selecting by md5.
+pub(crate) fn load_selection(
+    zip_path: &Path,
+    selector: &str,
+) -> Result<Vec<Signature>, Box<dyn std::error::Error>> {
+    let mut archive = zip::ZipArchive::new(File::open(zip_path)?)?;
+
+    let mut selected = vec![];
+    for entry in archive_entries(&mut archive, zip_path, Some(selector))? {
+        for mut sig in read_entry(&mut archive, &entry)? {
+            if sig.name() != selector {
+                let sketches: Vec<Sketch> = sig
+                    .sketches()
+                    .into_iter()
+                    .filter(|sketch| match sketch {
+                        Sketch::MinHash(mh) => selects(selector, "", &mh.md5sum()),
+                        _ => false,
+                    })
+                    .collect();
+                sig.reset_sketches();
+                for sketch in sketches {
+                    sig.push(sketch);
+                }
+            }
+            if !sig.sketches().is_empty() {
+                selected.push(sig);
+            }
+        }
+    }
+
+    if selected.is_empty() {
+        return Err(format!(
+            "{}: no signature named or with md5 {}",
+            zip_path.display(),
+            selector
+        )
+        .into());
+    }
+    Ok(selected)
+}
+
+/// A manifest row, in the columns sourmash writes.
+#[derive(Serialize, Default)]
+struct ManifestRecord {
+    internal_location: String,
+    md5: String,
+    md5short: String,
+    ksize: u32,
+    moltype: &'static str,
+    num: u32,
+    scaled: u64,
+    n_hashes: usize,
+    with_abundance: u8,
+    name: String,
+    filename: String,
+}
+
+/// Writes single-sketch signatures into a new zip collection, with a
+/// manifest. Like `output::create`, the archive only appears under its
+/// final name once finished.
+pub(crate) struct ZipCollectionWriter {
+    zip: zip::ZipWriter<File>,
+    manifest: Vec<ManifestRecord>,
+    partial: PathBuf,
+    path: PathBuf,
+}
+
+impl ZipCollectionWriter {
+    pub(crate) fn create(path: &Path) -> std::io::Result<ZipCollectionWriter> {
+        let partial = output::partial_path(path);
+        Ok(ZipCollectionWriter {
+            zip: zip::ZipWriter::new(File::create(&partial)?),
+            manifest: vec![],
+            partial,
+            path: path.to_path_buf(),
+        })
+    }
+
+    /// Add a signature holding just `mh`.
+    pub(crate) fn add(
+        &mut self,
+        name: &str,
+        mh: &KmerMinHash,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let md5 = mh.md5sum();
+        let location = format!("signatures/{}.sig.gz", md5);
+        let sig = Signature::builder()
+            .hash_function("0.murmur64")
+            .name(Some(name.to_string()))
+            .filename(None)
+            .signatures(vec![Sketch::MinHash(mh.clone())])
+            .build();
+
+        // the entries are already gzipped, so store them as they are.
+        let options =
+            zip::write::FileOptions::default().compression_method(zip::CompressionMethod::Stored);
+        self.zip.start_file(location.as_str(), options)?;
+        let mut gz = GzEncoder::new(&mut self.zip, Compression::default());
+        serde_json::to_writer(&mut gz, &[sig])?;
+        gz.finish()?;
+
+        self.manifest.push(ManifestRecord {
+            internal_location: location,
+            md5short: md5[..8].to_string(),
+            md5,
+            ksize: ksize_of(mh),
+            moltype: compat::moltype_name(mh.hash_function()),
+            num: mh.num(),
+            scaled: mh.scaled(),
+            n_hashes: mh.size(),
+            with_abundance: u8::from(mh.track_abundance()),
+            name: name.to_string(),
+            filename: String::new(),
+        });
+        Ok(())
+    }
+
+    /// Write the manifest and move the archive into place.
+    pub(crate) fn finish(mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let options = zip::write::FileOptions::default();
+        self.zip.start_file(MANIFEST, options)?;
+        self.zip.write_all(b"# SOURMASH-MANIFEST-VERSION: 1.0\n")?;
+        {
+            let mut writer = csv::Writer::from_writer(&mut self.zip);
+            // an empty collection still has the manifest columns.
+            if self.manifest.is_empty() {
+                output::write_header::<ManifestRecord, _>(&mut writer)?;
+            }
+            for record in &self.manifest {
+                writer.serialize(record)?;
+            }
+            writer.flush()?;
+        }
+        self.zip.finish()?.sync_all()?;
+        std::fs::rename(&self.partial, &self.path)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,170 @@
+//! `--fair-split`: when several matches overlap the query about equally,
+//! closer than sampling noise alone could tell apart, the hashes they
+//! share are split between them instead of all going to whichever one
+//! gather happens to pick first.
+//!
+//! Overlaps are hash counts, so two overlaps `a >= b` are co-equal if
+//! `a - b` is within `z` standard deviations of it, `sqrt(a + b)`, as for
+//! two Poisson counts.
+//!
+//! Each member's share is then its unique overlap in the results, and
+//! members whose share falls below the threshold are left out of the group.
+
+use std::collections::HashMap;
+
+use sourmash::sketch::minhash::KmerMinHash;
+
+use crate::candidate::{intersect_hashes, Candidate};
+
+/// Whether an overlap of `other` hashes can't be told apart from `best`.
+pub fn coequal(best: u64, other: u64, z: f64) -> bool {
+    let diff = best.saturating_sub(other) as f64;
+    diff <= z * ((best + other) as f64).sqrt()
+}
+
+/// How many of the query hashes claimed by `group` each member is credited
+/// with: every hash counts for one over the number of members containing it.
+fn split_shares(query: &KmerMinHash, group: &[Candidate]) -> Vec<f64> {
+    let claimed: Vec<Vec<u64>> = group
+        .iter()
+        .map(|member| intersect_hashes(query, &member.minhash))
+        .collect();
+    let mut n_members: HashMap<u64, usize> = HashMap::new();
+    for hashes in &claimed {
+        for hash in hashes {
+            *n_members.entry(*hash).or_default() += 1;
+        }
+    }
+    claimed
+        .iter()
+        .map(|hashes| hashes.iter().map(|hash| 1.0 / n_members[hash] as f64).sum())
+        .collect()
+}
+
+/// Split the query hashes claimed by `group`, best match first, between
+/// its members. Members whose share would fall below `threshold_hashes`
+/// leave the group, and the rest is split again; they are returned
+/// separately, to go back to the candidates. The best match always stays.
+pub fn split_group(
+    query: &KmerMinHash,
+    mut group: Vec<Candidate>,
+    threshold_hashes: u64,
+) -> (Vec<(Candidate, f64)>, Vec<Candidate>) {
+    let mut left_out = vec![];
+    loop {
+        let shares = split_shares(query, &group);
+        let too_small = |(i, share): (usize, &f64)| i > 0 && *share < threshold_hashes as f64;
+        if !shares.iter().enumerate().any(too_small) {
+            return (group.into_iter().zip(shares).collect(), left_out);
+        }
+        let mut kept = vec![];
+        for (i, member) in group.into_iter().enumerate() {
+            if too_small((i, &shares[i])) {
+                left_out.push(member);
+            } else {
+                kept.push(member);
+            }
+        }
+        group = kept;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use sourmash::encodings::HashFunctions;
+
+    fn minhash(hashes: &[u64]) -> KmerMinHash {
+        let mut mh = KmerMinHash::new(1000, 31, HashFunctions::murmur64_DNA, 42, false, 0);
+        mh.add_many(hashes).unwrap();
+        mh
+    }
+
+    fn candidate(name: &str, hashes: &[u64]) -> Candidate {
+        Candidate::new(name.into(), minhash(hashes), hashes.len() as u64)
+    }
+
+    fn names_and_shares(split: &[(Candidate, f64)]) -> Vec<(&str, f64)> {
+        split
+            .iter()
+            .map(|(member, share)| (member.name.as_str(), *share))
+            .collect()
+    }
+
+    #[test]
+    fn coequal_within_noise() {
+        // sqrt(100 + 90) is about 13.8.
+        assert!(coequal(100, 90, 1.0));
+        assert!(!coequal(100, 80, 1.0));
+        assert!(coequal(100, 80, 2.0));
+        assert!(coequal(100, 100, 0.0));
+    }
+
+    #[test]
+    fn hash_shared_by_two() {
+        let query = minhash(&[1, 2, 3, 4, 5, 6]);
+        let group = vec![candidate("a", &[1, 2, 3, 4]), candidate("b", &[3, 4, 5, 6])];
+        let (split, left_out) = split_group(&query, group, 1);
+        assert_eq!(names_and_shares(&split), vec![("a", 3.0), ("b", 3.0)]);
+        assert!(left_out.is_empty());
+    }
+
+    #[test]
+    fn hash_shared_by_three() {
+        let query = minhash(&[1, 2, 3, 4, 5]);
+        let group = vec![
+            candidate("a", &[1, 2, 3]),
+            candidate("b", &[3, 4]),
+            candidate("c", &[3, 5]),
+        ];
+        let (split, _) = split_group(&query, group, 1);
+        let shares = names_and_shares(&split);
+        assert_eq!(shares[0], ("a", 2.0 + 1.0 / 3.0));
+        assert_eq!(shares[1], ("b", 1.0 + 1.0 / 3.0));
+        assert_eq!(shares[2], ("c", 1.0 + 1.0 / 3.0));
+    }
+
+    #[test]
+    fn shares_that_do_not_divide_evenly_add_up() {
+        let query = minhash(&[1, 2, 3, 4, 5, 6, 7]);
+        let group = vec![
+            candidate("a", &[1, 2, 3, 4, 5]),
+            candidate("b", &[4, 5, 6]),
+            candidate("c", &[5, 7]),
+        ];
+        let (split, _) = split_group(&query, group, 1);
+        let shares = names_and_shares(&split);
+        // 4 and 5 are shared by two and three members.
+        assert_eq!(shares[0], ("a", 3.0 + 1.0 / 2.0 + 1.0 / 3.0));
+        assert_eq!(shares[1], ("b", 1.0 + 1.0 / 2.0 + 1.0 / 3.0));
+        assert_eq!(shares[2], ("c", 1.0 + 1.0 / 3.0));
+        let total: f64 = shares.iter().map(|(_, share)| share).sum();
+        assert!((total - 7.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn small_shares_leave_the_group() {
+        let query = minhash(&[1, 2, 3, 4, 5, 6]);
+        let group = vec![
+            candidate("a", &[1, 2, 3, 4]),
+            candidate("b", &[1, 2, 3, 5]),
+            candidate("c", &[4, 6]),
+        ];
+        // c's share is 1.5 and b's 2.5 at first; c leaves, a and b split again.
+        let (split, left_out) = split_group(&query, group, 2);
+        assert_eq!(names_and_shares(&split), vec![("a", 2.5), ("b", 2.5)]);
+        assert_eq!(left_out.len(), 1);
+        assert_eq!(left_out[0].name, "c");
+    }
+
+    #[test]
+    fn best_match_always_stays() {
+        let query = minhash(&[1, 2, 3]);
+        let group = vec![candidate("a", &[1, 2]), candidate("b", &[1, 2, 3])];
+        // alone, a gets both the hashes it shared with b.
+        let (split, left_out) = split_group(&query, group, 5);
+        assert_eq!(names_and_shares(&split), vec![("a", 2.0)]);
+        assert_eq!(left_out[0].name, "b");
+    }
+}
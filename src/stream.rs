@@ -0,0 +1,194 @@
+//! Gather candidates that are never kept in memory: every round rescans
+//! the matchlist files from disk and keeps only the best match seen.
+//!
+//! Only the paths of files that still reach the threshold are remembered
+//! between rounds, so memory use stays close to that of one loaded file per
+//! thread, at the cost of reading every candidate file each round.
+
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use log::warn;
+use rayon::prelude::*;
+use sourmash::sketch::minhash::KmerMinHash;
+use sourmash::sketch::Sketch;
+
+use crate::index::{Index, RankBy};
+use crate::loader::{self, RetryPolicy};
+use crate::{screen, select_candidate, Candidate, Label};
+
+pub(crate) struct StreamingMatches {
+    paths: Vec<PathBuf>,
+    labels: HashMap<PathBuf, Label>,
+    template: Sketch,
+    cache_dir: Option<PathBuf>,
+    retry: RetryPolicy,
+    n_scans: usize,
+    /// files that failed to load on a rescan; they are not scanned again
+    failed: Mutex<Vec<PathBuf>>,
+}
+
+/// A scored candidate, and the position of its file in the path list.
+type Scored = (f64, usize, Candidate);
+
+/// The better of two scored candidates, ranked as `HeapIndex` ranks them;
+/// remaining ties go to the earlier file.
+fn better(a: Scored, b: Scored) -> Scored {
+    let ((score_a, i_a, result_a), (score_b, i_b, result_b)) = (&a, &b);
+    let order = score_a
+        .total_cmp(score_b)
+        .then_with(|| result_a.cmp(result_b))
+        .then_with(|| result_b.name.cmp(&result_a.name))
+        .then_with(|| i_b.cmp(i_a));
+    if order == Ordering::Less {
+        b
+    } else {
+        a
+    }
+}
+
+impl StreamingMatches {
+    /// `paths` are the matchlist files known to reach the threshold.
+    pub(crate) fn new(
+        paths: Vec<PathBuf>,
+        labels: HashMap<PathBuf, Label>,
+        template: &Sketch,
+        cache_dir: Option<&Path>,
+        retry: RetryPolicy,
+    ) -> StreamingMatches {
+        StreamingMatches {
+            paths,
+            labels,
+            template: template.clone(),
+            cache_dir: cache_dir.map(Path::to_path_buf),
+            retry,
+            n_scans: 0,
+            failed: Mutex::new(vec![]),
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.paths.len()
+    }
+
+    /// How many times the candidate files were read so far.
+    pub(crate) fn n_scans(&self) -> usize {
+        self.n_scans
+    }
+
+    /// Forget the files that failed to load, returning them.
+    pub(crate) fn take_failed(&mut self) -> Vec<PathBuf> {
+        std::mem::take(self.failed.get_mut().unwrap())
+    }
+
+    /// Load the `i`th candidate file and pick its match, if it still
+    /// reaches the threshold. A file that fails to load is recorded.
+    fn scan_one(
+        &self,
+        i: usize,
+        screen: &screen::QueryScreen,
+        threshold_hashes: u64,
+    ) -> Option<Candidate> {
+        let path = &self.paths[i];
+        let sigs = match loader::load_with_retry(path, self.cache_dir.as_deref(), &self.retry) {
+            Ok(sigs) => sigs,
+            Err(e) => {
+                warn!("could not load {}: {}", path.display(), e);
+                self.failed.lock().unwrap().push(path.clone());
+                return None;
+            }
+        };
+        select_candidate(&sigs, &self.template, screen, threshold_hashes, false).map(|result| {
+            result
+                .with_filename(path.display().to_string())
+                .labelled(self.labels.get(path))
+        })
+    }
+
+    /// Read every candidate file, forget those that no longer reach the
+    /// threshold, and remove and return the best match for the query.
+    pub(crate) fn pop_best(
+        &mut self,
+        query: &KmerMinHash,
+        threshold_hashes: u64,
+        rank_by: RankBy,
+    ) -> Option<Candidate> {
+        self.n_scans += 1;
+        let screen = screen::QueryScreen::new(query);
+        let (mut kept, best) = (0..self.paths.len())
+            .into_par_iter()
+            .fold(
+                || (vec![], None),
+                |(mut kept, best): (Vec<usize>, Option<Scored>), i| {
+                    let result = match self.scan_one(i, &screen, threshold_hashes) {
+                        Some(result) => result,
+                        None => return (kept, best),
+                    };
+                    kept.push(i);
+                    let scored = (rank_by.score(&result, query), i, result);
+                    let best = match best {
+                        Some(best) => better(best, scored),
+                        None => scored,
+                    };
+                    (kept, Some(best))
+                },
+            )
+            .reduce(
+                || (vec![], None),
+                |(mut kept_a, best_a), (kept_b, best_b)| {
+                    kept_a.extend(kept_b);
+                    let best = match (best_a, best_b) {
+                        (Some(a), Some(b)) => Some(better(a, b)),
+                        (a, b) => a.or(b),
+                    };
+                    (kept_a, best)
+                },
+            );
+
+        let best = best.map(|(_, i, result)| {
+            kept.retain(|&j| j != i);
+            result
+        });
+        kept.sort_unstable();
+        let mut paths = std::mem::take(&mut self.paths);
+        self.paths = kept
+            .into_iter()
+            .map(|i| std::mem::take(&mut paths[i]))
+            .collect();
+        best
+    }
+}
+
+impl Index for StreamingMatches {
+    fn len(&self) -> usize {
+        StreamingMatches::len(self)
+    }
+
+    /// Nothing is kept to report; `--no-prefetch` excludes
+    /// `--output-prefetch`.
+    fn prefetch(&self) -> Vec<Candidate> {
+        vec![]
+    }
+
+    fn best_match(
+        &mut self,
+        query: &KmerMinHash,
+        threshold_hashes: u64,
+        rank_by: RankBy,
+    ) -> Result<Option<Candidate>, Box<dyn std::error::Error>> {
+        Ok(self.pop_best(query, threshold_hashes, rank_by))
+    }
+
+    /// Overlaps are recomputed on every scan.
+    fn remove_hashes(&mut self, _query: &KmerMinHash, _threshold_hashes: u64) {}
+
+    fn stop_loading(&mut self) -> Vec<std::path::PathBuf> {
+        self.take_failed()
+    }
+
+    fn finish(&self) {
+        log::debug!("scanned the candidate files {} times", self.n_scans());
+    }
+}
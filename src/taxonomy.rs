@@ -0,0 +1,186 @@
+//! `--taxonomy`: summarize gather results by lineage, from a lineage
+//! spreadsheet or the lineages in a sourmash LCA database, into
+//! `<output>.summarized.csv`.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use log::info;
+use serde::Serialize;
+
+use crate::lca::LcaDatabase;
+use crate::{output, GatherResult};
+
+const RANKS: [&str; 8] = [
+    "superkingdom",
+    "phylum",
+    "class",
+    "order",
+    "family",
+    "genus",
+    "species",
+    "strain",
+];
+
+/// Identifier -> lineage mapping, loaded from a sourmash-style lineage
+/// spreadsheet (`ident,superkingdom,phylum,...`).
+pub struct Taxonomy {
+    ranks: Vec<String>,
+    lineages: HashMap<String, Vec<String>>,
+}
+
+#[derive(Serialize)]
+struct SummaryRow<'a> {
+    query_name: &'a str,
+    rank: &'a str,
+    fraction: f64,
+    lineage: String,
+}
+
+/// GTDB/NCBI identifiers without their version suffix, e.g. `GCA_1234.1`
+/// becomes `GCA_1234`; only a trailing `.<digits>` is a version, so names
+/// like `E.coli` stay as they are.
+fn strip_version(ident: &str) -> &str {
+    match ident.rsplit_once('.') {
+        Some((base, version))
+            if !version.is_empty() && version.bytes().all(|b| b.is_ascii_digit()) =>
+        {
+            base
+        }
+        _ => ident,
+    }
+}
+
+impl Taxonomy {
+    pub fn from_csv<P: AsRef<Path>>(path: P) -> Result<Taxonomy, Box<dyn std::error::Error>> {
+        let mut reader = csv::Reader::from_path(path.as_ref())?;
+        let headers = reader.headers()?.clone();
+
+        let ident_col = headers
+            .iter()
+            .position(|h| ["ident", "identifiers", "accession"].contains(&h))
+            .ok_or("lineage spreadsheet has no ident/identifiers/accession column")?;
+        let rank_cols: Vec<(usize, String)> = headers
+            .iter()
+            .enumerate()
+            .filter(|(_, h)| RANKS.contains(h))
+            .map(|(i, h)| (i, h.to_string()))
+            .collect();
+        if rank_cols.is_empty() {
+            return Err("lineage spreadsheet has no rank columns".into());
+        }
+
+        let mut lineages = HashMap::new();
+        for record in reader.records() {
+            let record = record?;
+            let ident = strip_version(&record[ident_col]).to_string();
+            let lineage = rank_cols
+                .iter()
+                .map(|(i, _)| record.get(*i).unwrap_or("").to_string())
+                .collect();
+            lineages.insert(ident, lineage);
+        }
+        info!(
+            "Loaded {} lineages from {}",
+            lineages.len(),
+            path.as_ref().display()
+        );
+
+        Ok(Taxonomy {
+            ranks: rank_cols.into_iter().map(|(_, h)| h).collect(),
+            lineages,
+        })
+    }
+
+    /// Use the lineages stored in a sourmash LCA database.
+    pub fn from_lca(db: &LcaDatabase) -> Taxonomy {
+        let lineages = db.lineages();
+        let ranks: Vec<String> = RANKS
+            .iter()
+            .filter(|rank| {
+                lineages
+                    .iter()
+                    .any(|(_, lineage)| lineage.iter().any(|(r, _)| r == *rank))
+            })
+            .map(|rank| rank.to_string())
+            .collect();
+
+        let lineages: HashMap<String, Vec<String>> = lineages
+            .into_iter()
+            .map(|(ident, lineage)| {
+                let names = ranks
+                    .iter()
+                    .map(|rank| {
+                        lineage
+                            .iter()
+                            .find(|(r, _)| r == rank)
+                            .map(|(_, name)| name.clone())
+                            .unwrap_or_default()
+                    })
+                    .collect();
+                (strip_version(ident).to_string(), names)
+            })
+            .collect();
+        info!("Using {} lineages from the LCA database", lineages.len());
+
+        Taxonomy { ranks, lineages }
+    }
+
+    fn lineage_for(&self, name: &str) -> Option<&Vec<String>> {
+        let ident = crate::ident_for_name(name);
+        self.lineages
+            .get(ident)
+            .or_else(|| self.lineages.get(strip_version(ident)))
+    }
+
+    /// Write, for each rank, the fraction of the query assigned to each
+    /// lineage; whatever was not assigned is reported as `unclassified`.
+    pub fn write_summary<P: AsRef<Path>>(
+        &self,
+        path: P,
+        query_name: &str,
+        results: &[GatherResult],
+    ) -> Result<(), csv::Error> {
+        let mut writer = output::create_csv(path)?;
+
+        for (depth, rank) in self.ranks.iter().enumerate() {
+            let mut fractions: Vec<(String, f64)> = vec![];
+            let mut classified = 0.0;
+            for result in results {
+                if let Some(lineage) = self.lineage_for(&result.ident) {
+                    let lineage = lineage[..=depth].join(";");
+                    match fractions.iter_mut().find(|(l, _)| *l == lineage) {
+                        Some((_, f)) => *f += result.f_unique_to_query,
+                        None => fractions.push((lineage, result.f_unique_to_query)),
+                    }
+                    classified += result.f_unique_to_query;
+                }
+            }
+
+            fractions.sort_by(|a, b| b.1.total_cmp(&a.1));
+            fractions.push(("unclassified".to_string(), 1.0 - classified));
+
+            for (lineage, fraction) in fractions {
+                writer.serialize(SummaryRow {
+                    query_name,
+                    rank,
+                    fraction,
+                    lineage,
+                })?;
+            }
+        }
+        output::finish_csv(writer)?;
+        Ok(())
+    }
+}
+
+/// `out.csv` -> `out.summarized.csv`, next to the gather output.
+pub fn summary_path<P: AsRef<Path>>(output: P) -> PathBuf {
+    let output = output.as_ref();
+    let stem = output
+        .file_name()
+        .map(|f| f.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let stem = stem.strip_suffix(".csv").unwrap_or(&stem);
+    output.with_file_name(format!("{}.summarized.csv", stem))
+}
@@ -0,0 +1,66 @@
+//! `--early-start-bp` runs that find no candidates still report the
+//! matchlist files that failed to load in the background.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// A signature file holding one scaled=1000, k=31 sketch of `hashes`.
+fn write_sig(path: &Path, name: &str, hashes: impl Iterator<Item = u64>) {
+    let mins: Vec<String> = hashes.map(|hash| hash.to_string()).collect();
+    let sig = format!(
+        r#"[{{"class": "sourmash_signature", "email": "", "hash_function": "0.murmur64",
+        "filename": "{name}", "name": "{name}", "license": "CC0",
+        "signatures": [{{"num": 0, "ksize": 31, "seed": 42, "max_hash": 18446744073709552,
+        "mins": [{mins}], "md5sum": "", "molecule": "DNA"}}], "version": 0.4}}]"#,
+        name = name,
+        mins = mins.join(", ")
+    );
+    fs::write(path, sig).unwrap();
+}
+
+fn scratch_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("countergather-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[test]
+fn no_candidates_with_a_failed_background_load() {
+    let dir = scratch_dir("early-start");
+    let query = dir.join("query.sig");
+    let other = dir.join("other.sig");
+    let missing = dir.join("missing.sig");
+    let matchlist = dir.join("matchlist.txt");
+    let summary = dir.join("summary.json");
+
+    // no hash in common with the query.
+    write_sig(&query, "query", (1..=100).map(|i| i * 1000));
+    write_sig(&other, "other", (1..=100).map(|i| i * 1000 + 1));
+    fs::write(
+        &matchlist,
+        format!("{}\n{}\n", other.display(), missing.display()),
+    )
+    .unwrap();
+
+    let status = Command::new(env!("CARGO_BIN_EXE_countergather"))
+        .arg(&query)
+        .arg(&matchlist)
+        .args(["--early-start-bp", "1000", "-q"])
+        .arg("--summary-json")
+        .arg(&summary)
+        .status()
+        .unwrap();
+    assert_eq!(status.code(), Some(5));
+
+    let summary: serde_json::Value =
+        serde_json::from_str(&fs::read_to_string(&summary).unwrap()).unwrap();
+    assert_eq!(summary["status"], "partial_load");
+    assert_eq!(
+        summary["failed_files"],
+        serde_json::json!([missing.display().to_string()])
+    );
+
+    fs::remove_dir_all(&dir).unwrap();
+}